@@ -1,43 +1,137 @@
-use git2::{build::CheckoutBuilder, ObjectType, Oid, Repository};
+use git2::{build::CheckoutBuilder, ApplyLocation, Diff, ObjectType, Oid, Repository, ResetType, WorktreeAddOptions, WorktreePruneOptions};
 use rand::{seq::SliceRandom, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use serde::{Serialize, Deserialize};
 use tera::{Tera, Context};
-use std::{collections::HashMap, fs, io::{Read, Write}, path::{Path, PathBuf}, process::Command};
-use crate::{config, utils};
+use std::{collections::HashMap, fs, io::{Read, Write}, path::{Path, PathBuf}, sync::Mutex, thread, time::{SystemTime, UNIX_EPOCH}};
+use crate::{config, logs, patches, runner, utils};
 use walkdir::WalkDir;
 
 pub const CONTROL_REPO_DIR : &str = ".control";
 pub const LOCKFILE_FILE: &str = "lockfile.toml";
 pub const BUILD_DIR: &str = ".bipolar";
 
+// stage bits of IndexEntry.flags, conflict sides are staged as 1..=3
+const INDEX_ENTRY_STAGE_MASK: u16 = 0x3000;
+
+// hash used to seed the shard shuffle. a lockfile keeps the version it was
+// built with, so changing the default never reshuffles a running experiment
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashVersion {
+    // std's DefaultHasher, output may change with the rust toolchain.
+    // lockfiles written before the version was recorded use this
+    #[default]
+    Legacy,
+    // utils::stable_hash of the seed and the treatment name
+    V1,
+    // V1, and under Proxy every layer gets its own order of minmax instead
+    // of them all stacking on the first shards
+    V2,
+}
+
+pub const CURRENT_HASH_VERSION: HashVersion = HashVersion::V2;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LockFile {
+    #[serde(default)]
+    pub hash_version: HashVersion,
     pub assignment: config::Assignment,
+    #[serde(default)]
+    pub layout: config::ShardLayout,
     pub base: String,
     pub repo: String,
     pub shard_count: usize,
     pub minmax: (usize, usize),
     pub applied: HashMap<String, Vec<usize>>,
+    // treatment -> conflicts that were resolved by its on_conflict policy
+    #[serde(default)]
+    pub conflicts: HashMap<String, Vec<ConflictResolution>>,
+    // patch treatment -> fingerprint of the series it was applied with
+    #[serde(default)]
+    pub fingerprints: HashMap<String, String>,
+    // shards held out by assignment.control, out of all shard_count
+    #[serde(default)]
+    pub control: Vec<usize>,
+    // the commit `base` resolved to
+    #[serde(default)]
+    pub base_oid: Option<String>,
+    // branch and commit treatment -> the commit that gets merged
+    #[serde(default)]
+    pub resolved: HashMap<String, String>,
+    // shard index -> how its last build went
+    #[serde(default)]
+    pub builds: HashMap<String, ShardBuild>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShardBuild {
+    // unix seconds
+    pub finished_at: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConflictResolution {
+    pub shard: usize,
+    pub policy: config::ConflictPolicy,
+    pub paths: Vec<String>,
+}
+
+// something that keeps a config from being built on top of the current
+// build, values are as they'd be printed
+#[derive(Debug)]
+pub struct Difference {
+    pub field: String,
+    pub old: String,
+    pub new: String,
 }
 
+fn strategy_label(strategy: &config::StrategyType) -> String {
+    match strategy {
+        config::StrategyType::Random(random) => format!("Random (seed {})", random.seed),
+        config::StrategyType::Proxy(_) => "Proxy".to_string(),
+    }
+}
+
+// splits aren't compared, build ramps treatments up and down on the shards
+// that need it
 impl LockFile {
-    fn eq(&self, lockfile: &LockFile) -> bool {
-        self.base == lockfile.base
-            && self.repo == lockfile.repo
-            && self.shard_count == lockfile.shard_count
-            && self.minmax == lockfile.minmax
-            && self.assignment.split.iter().all(|(k, v)| lockfile.assignment.split.get(k).map_or(false, |bv| bv >= v))
-            && match (&self.assignment.strategy, &lockfile.assignment.strategy) {
-                (config::StrategyType::Random(r1), config::StrategyType::Random(r2)) => r1.seed == r2.seed,
-                _ => false,
+    fn differences(&self, lockfile: &LockFile) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        let mut compare = |field: &str, old: String, new: String| {
+            if old != new {
+                differences.push(Difference { field: field.to_string(), old, new });
             }
+        };
+
+        compare("base", self.base.clone(), lockfile.base.clone());
+        compare("layout", format!("{:?}", self.layout), format!("{:?}", lockfile.layout));
+        compare("repo", self.repo.clone(), lockfile.repo.clone());
+        compare("shard_count", self.shard_count.to_string(), lockfile.shard_count.to_string());
+        compare("minmax", format!("{:?}", self.minmax), format!("{:?}", lockfile.minmax));
+        compare(
+            "assignment.control",
+            format!("{}%", self.assignment.control.unwrap_or(0)),
+            format!("{}%", lockfile.assignment.control.unwrap_or(0)),
+        );
+        compare(
+            "assignment.strategy",
+            strategy_label(&self.assignment.strategy),
+            strategy_label(&lockfile.assignment.strategy),
+        );
+
+        differences
     }
 }
 
+// like get_build_dir, without creating it
+fn build_dir_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config::get_base()?.join(BUILD_DIR))
+}
+
 pub fn get_build_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut base = config::get_base()?;
-    base.push(BUILD_DIR);
+    let base = build_dir_path()?;
 
     if !base.exists() {
         std::fs::create_dir_all(&base)?;
@@ -47,38 +141,62 @@ pub fn get_build_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
 }
 
 pub fn get_lockfile_path() -> Result<String, Box<dyn std::error::Error>> {
-    let mut path = get_build_dir()?;
+    let mut path = build_dir_path()?;
     path.push(LOCKFILE_FILE);
 
     Ok(path.to_str().unwrap_or("unknown").to_string())
 }
 
-fn compare_lockfile(
-    lockfile: &LockFile,
-) -> Result<LockFile, Box<dyn std::error::Error>> {
+pub fn read_lockfile() -> Result<LockFile, Box<dyn std::error::Error>> {
     let path = get_lockfile_path()?;
     let mut file = fs::File::open(path)?;
 
     let mut contents = String::new();
     let _ = file.read_to_string(&mut contents);
-    let current_lockfile: LockFile = toml::from_str(&contents)?;
 
-    if current_lockfile.eq(lockfile) {
+    Ok(toml::from_str(&contents)?)
+}
+
+// the current lockfile when `lockfile` can be built on top of it, otherwise
+// everything that's in the way
+fn compare_lockfile(
+    lockfile: &LockFile,
+) -> Result<LockFile, Vec<Difference>> {
+    let current_lockfile = match read_lockfile() {
+        Ok(current_lockfile) => current_lockfile,
+        Err(e) => {
+            let exists = get_lockfile_path().is_ok_and(|path| Path::new(&path).exists());
+            let old = if exists { format!("unreadable ({})", e) } else { "missing".to_string() };
+
+            return Err(vec![Difference { field: "lockfile".to_string(), old, new: "fresh".to_string() }]);
+        }
+    };
+
+    let differences = current_lockfile.differences(lockfile);
+    if differences.is_empty() {
         return Ok(current_lockfile);
     }
 
-    Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "not equal")))
+    Err(differences)
 }
 
 fn form_lockfile(config: &config::ExperimentConfig) -> LockFile {
-    return LockFile {
+    LockFile {
+        hash_version: CURRENT_HASH_VERSION,
         assignment: config.assignment.clone(),
+        layout: config.layout.unwrap_or_default(),
         base: config.base.clone(),
         repo: config.repo.clone(),
         shard_count: config.shard_count,
         minmax: config.minmax,
         applied: HashMap::new(),
-    };
+        conflicts: HashMap::new(),
+        fingerprints: HashMap::new(),
+        control: Vec::new(),
+        base_oid: None,
+        resolved: HashMap::new(),
+        builds: HashMap::new(),
+    }
 }
 
 fn write_lockfile(lockfile: &LockFile) -> Result<(), Box<dyn std::error::Error>> {
@@ -93,32 +211,88 @@ fn write_lockfile(lockfile: &LockFile) -> Result<(), Box<dyn std::error::Error>>
     }
 }
 
+pub fn shard_treatments(lockfile: &LockFile, shard: usize) -> Vec<String> {
+    let mut treatments: Vec<String> = lockfile.applied.iter()
+        .filter(|(_, shards)| shards.contains(&shard))
+        .map(|(name, _)| name.clone())
+        .collect();
+    treatments.sort();
+
+    treatments
+}
+
+// handed to the build and run hooks of every shard
+pub fn shard_env(config: &config::ExperimentConfig, lockfile: &LockFile, shard: usize) -> Vec<(String, String)> {
+    let mut envs = vec![
+        ("BIPOLAR_SHARD".to_string(), shard.to_string()),
+        ("BIPOLAR_TREATMENTS".to_string(), shard_treatments(lockfile, shard).join(",")),
+    ];
+
+    if let Some(port) = config.shard_port(shard) {
+        envs.push(("BIPOLAR_PORT".to_string(), port.to_string()));
+    }
+
+    envs
+}
+
 pub fn get_shard_dir(shard: usize) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let mut path = get_build_dir()?;
+    let mut path = build_dir_path()?;
     path.push(format!("shard_{}", shard));
 
     Ok(path)
 }
 
+// every shard gets its own branch off the control HEAD, so treatments can
+// be merged into it without touching the control checkout or other shards
+fn add_shard_worktree(control_repo: &Repository, shard: usize, shard_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let name = format!("shard_{}", shard);
+
+    // leftovers of a shard directory that was deleted by hand
+    if let Ok(worktree) = control_repo.find_worktree(&name) {
+        worktree.prune(Some(WorktreePruneOptions::new().valid(true).working_tree(true)))?;
+    }
+
+    let head = control_repo.head()?.peel_to_commit()?;
+    let branch = control_repo.branch(&format!("bipolar/{}", name), &head, true)?;
+
+    let mut options = WorktreeAddOptions::new();
+    options.reference(Some(branch.get()));
+    control_repo.worktree(&name, shard_path, Some(&options))?;
+
+    Ok(())
+}
+
+// returns the shards that didn't exist yet
 fn populate_shard_repos(
     config: &config::ExperimentConfig,
     control_repo_path: &Path,
-) -> Result<HashMap<usize, Repository>, Box<dyn std::error::Error>> {
-    let mut storage = HashMap::new();
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut created = Vec::new();
+    let layout = config.layout.unwrap_or_default();
 
     for i in config.minmax.0..config.minmax.1 {
         let shard_path = get_shard_dir(i)?;
         if !shard_path.exists() {
-            utils::copy_dir_recursive(&control_repo_path, &shard_path)?;
+            match layout {
+                config::ShardLayout::Copy => utils::copy_dir_recursive(control_repo_path, &shard_path)?,
+                config::ShardLayout::Worktree => {
+                    let control_repo = Repository::open(control_repo_path)?;
+                    add_shard_worktree(&control_repo, i, &shard_path)?;
+                }
+            }
+            created.push(i);
         }
-        let repo = Repository::open(&shard_path)?;
-        storage.insert(i, repo);
     }
 
-    Ok(storage)
+    Ok(created)
 }
 
-pub fn clone_control_repo(config: &config::ExperimentConfig, path: &PathBuf) -> Result<PathBuf, Box<dyn std::error::Error>> {
+// checks out `pin` instead of the configured base when given
+pub fn clone_control_repo(
+    config: &config::ExperimentConfig,
+    path: &Path,
+    pin: Option<Oid>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
     println!("cloning control repo from {}", config.repo);
 
     let control_repo_path = path.join(CONTROL_REPO_DIR);
@@ -127,7 +301,14 @@ pub fn clone_control_repo(config: &config::ExperimentConfig, path: &PathBuf) ->
         &control_repo_path,
     )?;
 
-    let (object, reference) = control_repo.revparse_ext(&config.base)?;
+    let (object, reference) = match pin {
+        Some(oid) => {
+            let object = control_repo.find_object(oid, None)
+                .map_err(|e| format!("pinned base {} isn't in {}: {}", oid, config.repo, e))?;
+            (object, None)
+        }
+        None => control_repo.revparse_ext(&config.base)?,
+    };
 
     control_repo.checkout_tree(&object, None)?;
 
@@ -136,11 +317,13 @@ pub fn clone_control_repo(config: &config::ExperimentConfig, path: &PathBuf) ->
         None => control_repo.set_head_detached(object.id())
     }?;
 
-    if config.hooks.control_build.is_some() {
+    if let Some(control_build) = &config.hooks.control_build {
         println!("🔨 building control repo");
         utils::run_command_string(
-            &config.hooks.control_build.as_ref().unwrap(),
+            control_build,
             control_repo_path.to_str().expect("couldn't get control repo path, wtf"),
+            &[],
+            Some(&logs::control_log_target()?),
             false,
         )?;
     }
@@ -148,7 +331,65 @@ pub fn clone_control_repo(config: &config::ExperimentConfig, path: &PathBuf) ->
     Ok(control_repo_path)
 }
 
-fn merge_commit_into(repo: &Repository, commit: &git2::Commit) -> Result<(), Box<dyn std::error::Error>> {
+fn conflict_path(conflict: &git2::IndexConflict) -> String {
+    let entry = conflict.our.as_ref()
+        .or(conflict.their.as_ref())
+        .or(conflict.ancestor.as_ref())
+        .expect("conflict without any side, wtf");
+
+    String::from_utf8_lossy(&entry.path).to_string()
+}
+
+fn blob_content(repo: &Repository, entry: &git2::IndexEntry) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(repo.find_blob(entry.id)?.content().to_vec())
+}
+
+fn with_markers(
+    repo: &Repository,
+    conflict: git2::IndexConflict,
+    label: &str,
+) -> Result<Option<git2::IndexEntry>, Box<dyn std::error::Error>> {
+    let (ours, theirs) = match (conflict.our, conflict.their) {
+        (Some(ours), Some(theirs)) => (ours, theirs),
+        // modify/delete, there's nothing to put markers around
+        (ours, theirs) => return Ok(ours.or(theirs)),
+    };
+
+    let content = match &conflict.ancestor {
+        Some(ancestor) => {
+            let mut options = git2::MergeFileOptions::new();
+            options.our_label("base").their_label(label);
+
+            repo.merge_file_from_index(ancestor, &ours, &theirs, Some(&mut options))?
+                .content()
+                .to_vec()
+        }
+
+        // both sides added the file
+        None => {
+            let mut content = b"<<<<<<< base\n".to_vec();
+            content.extend(blob_content(repo, &ours)?);
+            content.extend(b"=======\n");
+            content.extend(blob_content(repo, &theirs)?);
+            content.extend(format!(">>>>>>> {}\n", label).as_bytes());
+            content
+        }
+    };
+
+    let mut entry = ours;
+    entry.id = repo.blob(&content)?;
+    entry.file_size = content.len() as u32;
+
+    Ok(Some(entry))
+}
+
+// returns the paths that conflicted and were resolved according to `policy`
+fn merge_commit_into(
+    repo: &Repository,
+    commit: &git2::Commit,
+    label: &str,
+    policy: config::ConflictPolicy,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let head_commit = repo.head()?.peel_to_commit()?;
     let ancestor = repo.merge_base(head_commit.id(), commit.id())?;
     let ancestor_commit = repo.find_commit(ancestor)?;
@@ -158,23 +399,41 @@ fn merge_commit_into(repo: &Repository, commit: &git2::Commit) -> Result<(), Box
     let ancestor_tree = ancestor_commit.tree()?;
 
     let mut index = repo.merge_trees(&ancestor_tree, &head_tree, &commit_tree, None)?;
+    let mut resolved = Vec::new();
 
     if index.has_conflicts() {
-        println!("😵‍💫 merge conflict detected");
-
         let conflicts: Vec<_> = index.conflicts()?.collect::<Result<_, _>>()?;
+        let paths: Vec<String> = conflicts.iter().map(conflict_path).collect();
 
-        for conflict in conflicts {
-            if let Some(ours) = conflict.our {
-                println!("‼️ using our version of {:?}", ours.path);
-                index.add(&ours)?;
-            } else if let Some(theirs) = conflict.their {
-                println!("‼️ using their version of {:?}", theirs.path);
-                index.add(&theirs)?;
-            }
+        if policy == config::ConflictPolicy::Fail {
+            return Err(format!(
+                "merge conflict in {} (set on_conflict to resolve it automatically)",
+                paths.join(", "),
+            ).into());
         }
 
-        index.write()?;
+        println!("😵‍💫 merge conflict detected, resolving with {:?}", policy);
+
+        for (conflict, path) in conflicts.into_iter().zip(paths) {
+            let entry = match policy {
+                config::ConflictPolicy::Ours => conflict.our,
+                config::ConflictPolicy::Theirs => conflict.their,
+                config::ConflictPolicy::KeepMarkers => with_markers(repo, conflict, label)?,
+                config::ConflictPolicy::Fail => unreachable!(),
+            };
+
+            index.conflict_remove(Path::new(&path))?;
+            match entry {
+                Some(mut entry) => {
+                    entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+                    index.add(&entry)?
+                }
+                None => index.remove_path(Path::new(&path))?,
+            }
+
+            println!("‼️ resolved {} with {:?}", path, policy);
+            resolved.push(path);
+        }
     }
 
     let tree_oid = index.write_tree_to(repo)?;
@@ -187,60 +446,144 @@ fn merge_commit_into(repo: &Repository, commit: &git2::Commit) -> Result<(), Box
         &sig,
         "bipolar: auto-merge treatment",
         &tree,
-        &[&head_commit, &commit],
+        &[&head_commit, commit],
     )?;
 
     let mut checkout = CheckoutBuilder::new();
     checkout.force();
 
     repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
-    repo.set_head("HEAD")?;
+
+    Ok(resolved)
+}
+
+// commits whatever is staged on top of HEAD
+fn commit_index(repo: &Repository, message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut index = repo.index()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let head_commit = repo.head()?.peel_to_commit()?;
+
+    let sig = repo.signature()?;
+    repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &[&head_commit])?;
 
     Ok(())
 }
 
-pub fn apply_treatment(
-    shard_repo: &Repository,
+// the commit a branch or commit treatment merges, patches don't have one
+pub fn resolve_treatment(
+    repo: &Repository,
     treatment: &config::Treatment,
-    target_dir: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<Option<Oid>, Box<dyn std::error::Error>> {
     match treatment {
         config::Treatment::Branch(branch_treatment) => {
             let branch = &branch_treatment.ref_;
-            let reference = shard_repo.find_reference(&format!("refs/remotes/origin/{branch}"))?;
+            let reference = repo.find_reference(&format!("refs/remotes/origin/{branch}"))?;
             let object = reference.peel(ObjectType::Commit)?;
-            let commit = object.into_commit().map_err(|_| "Not a commit")?;
 
-            merge_commit_into(shard_repo, &commit)?;
+            Ok(Some(object.id()))
         }
 
         config::Treatment::Commit(commit_treatment) => {
             let oid = Oid::from_str(&commit_treatment.ref_)?;
-            let commit = shard_repo.find_commit(oid)?;
 
-            merge_commit_into(shard_repo, &commit)?;
+            Ok(Some(repo.find_commit(oid)?.id()))
+        }
+
+        config::Treatment::Patch(_) => Ok(None),
+    }
+}
+
+// treatment name -> the commit it gets applied as, what the lockfile pins
+// when `pinned`. patch treatments have none
+fn resolve_commits(
+    config: &config::ExperimentConfig,
+    control_repo: &Repository,
+    pinned: Option<&LockFile>,
+) -> Result<HashMap<String, Oid>, Box<dyn std::error::Error>> {
+    let mut commits = HashMap::new();
+    for treatment in &config.treatments {
+        let name = treatment_name(treatment);
+
+        let commit = match pinned.map(|pinned| pinned.resolved.get(name)) {
+            None => resolve_treatment(control_repo, treatment)?,
+            Some(Some(pin)) => {
+                let commit = control_repo.find_commit(Oid::from_str(pin)?)
+                    .map_err(|e| format!("pinned commit {} of treatment {} is gone: {}", pin, name, e))?;
+                Some(commit.id())
+            }
+            Some(None) => None,
+        };
+
+        if let Some(commit) = commit {
+            commits.insert(name.clone(), commit);
+        }
+    }
+
+    Ok(commits)
+}
+
+// `commit` is what resolve_treatment returned for branch and commit treatments
+pub fn apply_treatment(
+    shard_repo: &Repository,
+    treatment: &config::Treatment,
+    commit: Option<Oid>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut resolved = Vec::new();
+
+    match treatment {
+        config::Treatment::Branch(_) | config::Treatment::Commit(_) => {
+            let name = treatment_name(treatment);
+            let commit = shard_repo.find_commit(commit.ok_or("treatment wasn't resolved to a commit")?)?;
+
+            resolved = merge_commit_into(shard_repo, &commit, name, treatment_on_conflict(treatment))?;
         }
 
         config::Treatment::Patch(patch_treatment) => {
-            let patch_path = &patch_treatment.patch;
-            let status = Command::new("git")
-                .arg("apply")
-                .arg("--whitespace=fix")
-                .arg("--directory")
-                .arg(target_dir)
-                .arg(patch_path)
-                .status()?;
-
-            if !status.success() {
-                return Err(format!("failed to apply patch: {:?}", patch_path).into());
+            let series = patches::load_series(patch_treatment)?;
+            let before = shard_repo.head()?.peel_to_commit()?;
+
+            for (i, patch) in series.iter().enumerate() {
+                let applied = Diff::from_buffer(&patch.contents)
+                    .and_then(|diff| shard_repo.apply(&diff, ApplyLocation::Both, None));
+
+                if let Err(e) = applied {
+                    // all or nothing, so a retry doesn't trip over half a series
+                    shard_repo.reset(before.as_object(), ResetType::Hard, None)?;
+                    return Err(format!(
+                        "patch {}/{} ({}) doesn't apply: {}",
+                        i + 1, series.len(), patch.label, e,
+                    ).into());
+                }
+
+                commit_index(shard_repo, &format!(
+                    "bipolar: apply patch treatment {} ({})",
+                    patch_treatment.name, patch.label,
+                ))?;
             }
         }
     }
 
-    Ok(())
+    Ok(resolved)
+}
+
+fn shuffle_hash(hash_version: HashVersion, seed: &u64, treatment_name: &str) -> u64 {
+    match hash_version {
+        HashVersion::Legacy => {
+            use std::collections::hash_map::DefaultHasher;
+            use std::hash::{Hash, Hasher};
+
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            treatment_name.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        HashVersion::V1 | HashVersion::V2 => utils::stable_hash(&[&seed.to_le_bytes(), treatment_name.as_bytes()]),
+    }
 }
 
 fn shuffled_shards(
+    hash_version: HashVersion,
     seed: &u64,
     treatment_name: &str,
     min: usize,
@@ -248,13 +591,7 @@ fn shuffled_shards(
 ) -> Vec<usize> {
     let mut shard_ids: Vec<usize> = (min..max).collect();
 
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-
-    let mut hasher = DefaultHasher::new();
-    seed.hash(&mut hasher);
-    treatment_name.hash(&mut hasher);
-    let hash = hasher.finish();
+    let hash = shuffle_hash(hash_version, seed, treatment_name);
 
     let mut seed_bytes = [0u8; 32];
     seed_bytes[..8].copy_from_slice(&hash.to_le_bytes());
@@ -265,25 +602,148 @@ fn shuffled_shards(
     shard_ids
 }
 
-fn get_home_dir(repo: &Repository) -> PathBuf{
-    let mut path = repo.path().to_path_buf();
-    path.pop();
-    path
+// shuffled with its own key so the holdout doesn't depend on treatments
+const CONTROL_SHUFFLE_KEY: &str = "bipolar:control";
+
+// the shards held out by `assignment.control`, sorted. with the Proxy
+// strategy they're taken from the end of minmax
+pub fn control_shards(
+    config: &config::ExperimentConfig,
+    hash_version: HashVersion,
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let percent = config.assignment.control.unwrap_or(0) as usize;
+    if percent > 100 {
+        return Err(format!("control holdout is {}%, it can't be more than 100%", percent).into());
+    }
+
+    let pool: Vec<usize> = match &config.assignment.strategy {
+        config::StrategyType::Random(random) =>
+            shuffled_shards(hash_version, &random.seed, CONTROL_SHUFFLE_KEY, 0, config.shard_count),
+
+        _ => (config.minmax.0..config.minmax.1).rev().collect(),
+    };
+
+    let count = ((pool.len() * percent) as f64 / 100.0).round() as usize;
+    let mut control = pool[..count].to_vec();
+    control.sort();
+
+    Ok(control)
+}
+
+// treatments that share shards. treatments in the same layer, or exclusive
+// with each other (directly or through another treatment), share a pool
+pub struct Pool<'a> {
+    // the layer that shows up first in the config, it keys the shuffle
+    pub layer: &'a String,
+    pub treatments: Vec<&'a config::Treatment>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Template {
-    shard: usize,
-    shard_count: usize,
-    custom: HashMap<String, String>,
+pub fn treatment_pools(config: &config::ExperimentConfig) -> Result<Vec<Pool<'_>>, Box<dyn std::error::Error>> {
+    // layer -> indices into config.treatments
+    let mut pools: Vec<(&String, Vec<usize>)> = Vec::new();
+    for (i, treatment) in config.treatments.iter().enumerate() {
+        let layer = treatment_layer(treatment);
+        match pools.iter_mut().find(|(name, _)| *name == layer) {
+            Some((_, members)) => members.push(i),
+            None => pools.push((layer, vec![i])),
+        }
+    }
+
+    for (i, treatment) in config.treatments.iter().enumerate() {
+        for other in treatment_exclusive_with(treatment) {
+            let j = config.treatments.iter()
+                .position(|t| treatment_name(t) == other)
+                .ok_or(format!("treatment {} is exclusive with unknown treatment {}", treatment_name(treatment), other))?;
+
+            let a = pools.iter().position(|(_, members)| members.contains(&i)).unwrap();
+            let b = pools.iter().position(|(_, members)| members.contains(&j)).unwrap();
+
+            // the pool that shows up first in the config keeps its shuffle
+            if a != b {
+                let (_, members) = pools.remove(a.max(b));
+                let keep = &mut pools[a.min(b)].1;
+                keep.extend(members);
+                keep.sort();
+            }
+        }
+    }
+
+    Ok(pools.into_iter()
+        .map(|(layer, members)| Pool {
+            layer,
+            treatments: members.iter().map(|&i| &config.treatments[i]).collect(),
+        })
+        .collect())
+}
+
+// treatment name -> the shards it belongs on, out of all `shard_count`.
+// each pool is shuffled on its own and hands its treatments consecutive
+// slices in config order, so they can't overlap
+pub fn assign_shards(
+    config: &config::ExperimentConfig,
+    hash_version: HashVersion,
+) -> Result<HashMap<String, Vec<usize>>, Box<dyn std::error::Error>> {
+    let control = control_shards(config, hash_version)?;
+
+    let mut assigned = HashMap::new();
+    for Pool { layer, treatments } in treatment_pools(config)? {
+        let total: usize = treatments.iter()
+            .filter_map(|t| config.assignment.split.get(treatment_name(t)))
+            .map(|split| *split as usize)
+            .sum();
+
+        if total > 100 {
+            let names: Vec<&str> = treatments.iter().map(|t| treatment_name(t).as_str()).collect();
+            return Err(format!(
+                "splits of {} add up to {}%, but they can't share shards",
+                names.join(", "), total,
+            ).into());
+        }
+
+        // a lone treatment shuffles with its own name, same as before layers
+        let pool: Vec<usize> = match &config.assignment.strategy {
+            config::StrategyType::Random(random) =>
+                shuffled_shards(hash_version, &random.seed, layer, 0, config.shard_count),
+
+            // nothing to seed it with, the layer name alone decides
+            _ if hash_version == HashVersion::V2 =>
+                shuffled_shards(hash_version, &0, layer, config.minmax.0, config.minmax.1),
+
+            _ => (config.minmax.0..config.minmax.1).collect(),
+        };
+        let pool: Vec<usize> = pool.into_iter().filter(|shard| !control.contains(shard)).collect();
+
+        // slice bounds are rounded from the running total, so the slices
+        // always add up to the layer's total split
+        let mut taken = 0;
+        for treatment in treatments {
+            let name = treatment_name(treatment);
+            let Some(&split) = config.assignment.split.get(name) else {
+                println!("⚠️ no split for treatment {}, skipping", name);
+                continue;
+            };
+
+            let start = ((pool.len() * taken) as f64 / 100.0).round() as usize;
+            taken += split as usize;
+            let end = ((pool.len() * taken) as f64 / 100.0).round() as usize;
+
+            let mut shards = pool[start..end].to_vec();
+            shards.sort();
+            assigned.insert(name.clone(), shards);
+        }
+    }
+
+    Ok(assigned)
 }
 
-fn template_fill(shard: usize, config: &config::ExperimentConfig, shard_dir: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+fn template_fill(shard: usize, config: &config::ExperimentConfig, shard_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
     let template_config = config.templating.as_ref().expect("template config expected, fn not meant to be called");
 
     let mut context = Context::new();
-    // TODO: port?
     context.insert("shard", &shard);
+    if let Some(port) = config.shard_port(shard) {
+        context.insert("port", &port);
+    }
     context.insert("shard_count", &config.shard_count);
     context.insert("custom", &template_config.config);
 
@@ -315,108 +775,1036 @@ fn template_fill(shard: usize, config: &config::ExperimentConfig, shard_dir: &Pa
     Ok(())
 }
 
-pub fn build(config: &config::ExperimentConfig, nuclear: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let path = get_build_dir()?;
-    let mut lockfile = form_lockfile(config);
+// runs `job` for every shard on up to `jobs` threads at once, results come
+// back sorted by shard
+fn for_each_shard<T: Send>(
+    shards: &[usize],
+    jobs: usize,
+    job: impl Fn(usize) -> T + Sync,
+) -> Vec<(usize, T)> {
+    let queue = Mutex::new(shards.iter().copied());
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(shards.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some(shard) = queue.lock().unwrap().next() else {
+                    break;
+                };
+
+                let result = job(shard);
+                results.lock().unwrap().push((shard, result));
+            });
+        }
+    });
 
-    let control_repo_path = path.join(CONTROL_REPO_DIR);
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(shard, _)| *shard);
+    results
+}
+
+fn base_commit_id() -> Result<Oid, Box<dyn std::error::Error>> {
+    let control_repo = Repository::open(get_build_dir()?.join(CONTROL_REPO_DIR))?;
+    let id = control_repo.head()?.peel_to_commit()?.id();
+
+    Ok(id)
+}
+
+// drops everything bipolar did to a shard, treatments get re-applied after
+fn reset_shard(shard_repo: &Repository, base: Oid) -> Result<(), Box<dyn std::error::Error>> {
+    let commit = shard_repo.find_commit(base)?;
+    shard_repo.reset(commit.as_object(), ResetType::Hard, None)?;
 
-    let mut nuke = nuclear;
-    match compare_lockfile(&lockfile) {
-        Ok(current_lockfile) => lockfile = current_lockfile,
-        Err(_) => nuke = true,
+    Ok(())
+}
+
+fn forget_shard(lockfile: &mut LockFile, shard: usize) {
+    for shards in lockfile.applied.values_mut() {
+        shards.retain(|s| *s != shard);
+    }
+    for resolutions in lockfile.conflicts.values_mut() {
+        resolutions.retain(|resolution| resolution.shard != shard);
     }
+}
 
-    if nuke {
-        println!("☢️ nuclear build triggered");
+// its split went down, the treatment is gone, or a split earlier in the layer
+// grew and took the shard over. the shard is forgotten so it goes back to
+// base and gets whatever it keeps re-applied, true when that's needed
+fn ramp_down(lockfile: &mut LockFile, shard: usize, target: &[&config::Treatment]) -> bool {
+    let current = shard_treatments(lockfile, shard);
+    let Some(dropped) = current.iter().find(|name| !target.iter().any(|t| treatment_name(t) == *name)) else {
+        return false;
+    };
 
-        std::fs::remove_dir_all(&path)?;
-        clone_control_repo(config, &path)?;
+    println!("🔀 shard {} no longer gets {}, rebuilding it", shard, dropped);
+    forget_shard(lockfile, shard);
+    true
+}
+
+#[derive(Default)]
+struct ShardPlan<'a> {
+    reset: bool,
+    treatments: Vec<&'a config::Treatment>,
+}
+
+// copied shards only have the commits they were copied with, anything
+// `bipolar update` fetched since has to come over from the control repo
+fn apply_treatments(
+    shard: usize,
+    plan: Option<&ShardPlan>,
+    layout: config::ShardLayout,
+    control_repo_path: &Path,
+    base: Oid,
+    commits: &HashMap<String, Oid>,
+    applied: &mut Vec<(String, Option<ConflictResolution>)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(plan) = plan else {
+        return Ok(());
+    };
+
+    let shard_repo = Repository::open(get_shard_dir(shard)?)?;
+    if layout == config::ShardLayout::Copy {
+        fetch_into_shard(&shard_repo, control_repo_path)?;
+    }
+
+    if plan.reset {
+        println!("⏪ resetting shard {} to base", shard);
+        reset_shard(&shard_repo, base)?;
+    }
+
+    let treatments = &plan.treatments;
+
+    for treatment in treatments {
+        let name = treatment_name(treatment);
+
+        println!("💉 applying treatment {} to shard {}", name, shard);
+        let paths = apply_treatment(&shard_repo, treatment, commits.get(name).copied())
+            .map_err(|e| format!("couldn't apply treatment {}: {}", name, e))?;
+
+        let resolution = (!paths.is_empty()).then(|| ConflictResolution {
+            shard,
+            policy: treatment_on_conflict(treatment),
+            paths,
+        });
+        applied.push((name.clone(), resolution));
+    }
+
+    Ok(())
+}
+
+fn build_shard(
+    config: &config::ExperimentConfig,
+    lockfile: &LockFile,
+    shard: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = get_shard_dir(shard)?;
+
+    if let Some(build_hook) = &config.hooks.build {
+        println!("🔨 building shard {}", shard);
+        utils::run_command_string(
+            build_hook,
+            path.to_str().unwrap_or("unknown"),
+            &shard_env(config, lockfile, shard),
+            Some(&logs::shard_log_target(lockfile, shard, "build")?),
+            false,
+        )?;
+    }
+
+    if config.templating.is_some() {
+        println!("📄 filling in config templates for shard {}", shard);
+        template_fill(shard, config, &path)?;
+    }
+
+    if let Some(symlinks) = &config.symlinks {
+        for symlink in symlinks {
+            let symlink_path = path.join(symlink);
+
+            let default_base = "symlinks/".to_string();
+            let base = config.symlinks_base.as_ref().unwrap_or(&default_base);
+            let original_path = config::get_base()?.join(base).join(symlink);
+
+            utils::create_symlink_force(original_path.to_str().unwrap(), symlink_path.to_str().unwrap())?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn treatment_name(treatment: &config::Treatment) -> &String {
+    match treatment {
+        config::Treatment::Branch(t) => &t.name,
+        config::Treatment::Commit(t) => &t.name,
+        config::Treatment::Patch(t) => &t.name,
+    }
+}
+
+pub fn treatment_layer(treatment: &config::Treatment) -> &String {
+    let layer = match treatment {
+        config::Treatment::Branch(t) => &t.layer,
+        config::Treatment::Commit(t) => &t.layer,
+        config::Treatment::Patch(t) => &t.layer,
+    };
+
+    layer.as_ref().unwrap_or(treatment_name(treatment))
+}
+
+pub fn treatment_exclusive_with(treatment: &config::Treatment) -> &[String] {
+    let exclusive_with = match treatment {
+        config::Treatment::Branch(t) => &t.exclusive_with,
+        config::Treatment::Commit(t) => &t.exclusive_with,
+        config::Treatment::Patch(t) => &t.exclusive_with,
+    };
+
+    exclusive_with.as_deref().unwrap_or(&[])
+}
+
+fn treatment_on_conflict(treatment: &config::Treatment) -> config::ConflictPolicy {
+    match treatment {
+        config::Treatment::Branch(t) => t.on_conflict.unwrap_or_default(),
+        config::Treatment::Commit(t) => t.on_conflict.unwrap_or_default(),
+        config::Treatment::Patch(_) => config::ConflictPolicy::Fail,
+    }
+}
+
+// runs the build hooks of every shard that hasn't failed yet
+fn build_shards(
+    config: &config::ExperimentConfig,
+    lockfile: &LockFile,
+    shards: &[usize],
+    jobs: usize,
+    failures: &mut Vec<(usize, String)>,
+) {
+    if config.hooks.build.is_none() && config.templating.is_none() && config.symlinks.is_none() {
+        println!("⚠️ templating config and build hook missing!");
+        return;
+    }
+
+    let buildable: Vec<usize> = shards.iter()
+        .copied()
+        .filter(|shard| !failures.iter().any(|(failed, _)| failed == shard))
+        .collect();
+
+    let built = for_each_shard(&buildable, jobs, |shard| {
+        build_shard(config, lockfile, shard).map_err(|e| e.to_string())
+    });
+
+    for (shard, result) in built {
+        if let Err(e) = result {
+            failures.push((shard, e));
+        }
+    }
+}
+
+fn record_builds(lockfile: &mut LockFile, shards: &[usize], failures: &[(usize, String)]) {
+    let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    for shard in shards {
+        let error = failures.iter().find(|(failed, _)| failed == shard).map(|(_, e)| e.clone());
+        lockfile.builds.insert(shard.to_string(), ShardBuild { finished_at, error });
+    }
+}
+
+fn report_failures(failures: &mut [(usize, String)], total: usize) -> Result<(), Box<dyn std::error::Error>> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    failures.sort_by_key(|(shard, _)| *shard);
+
+    eprintln!("❌ {} of {} shards failed:", failures.len(), total);
+    for (shard, e) in failures.iter() {
+        eprintln!("  shard {}: {} (see {})", shard, e, logs::get_log_path(*shard, "build")?.display());
+    }
+
+    Err(format!("{} shards failed to build", failures.len()).into())
+}
+
+// a running experiment keeps serving the old build until its shards restart
+fn hint_restart(shards: &[usize]) {
+    if !shards.is_empty() && runner::read_state().is_ok_and(|state| utils::pid_alive(state.pid, false)) {
+        println!("💡 the runner still serves the old build, `bipolar restart --rolling` swaps it in shard by shard");
+    }
+}
+
+// the lockfile to build on top of, or a fresh one when everything has to go
+fn starting_lockfile(config: &config::ExperimentConfig, nuclear: bool) -> (LockFile, bool) {
+    let lockfile = form_lockfile(config);
+    if nuclear {
+        return (lockfile, true);
+    }
+
+    let (lockfile, nuke) = match compare_lockfile(&lockfile) {
+        Ok(current_lockfile) => (current_lockfile, false),
+        Err(differences) => {
+            println!("☢️ the config can't be built on top of the current build:");
+            for difference in &differences {
+                println!("  {}: {} -> {}", difference.field, difference.old, difference.new);
+            }
+
+            (lockfile, true)
+        }
+    };
+
+    let proxied = matches!(config.assignment.strategy, config::StrategyType::Proxy(_));
+    match lockfile.hash_version {
+        HashVersion::Legacy => println!(
+            "⚠️ lockfile uses the {:?} shard hash, which can reshuffle shards between rust releases. run a nuclear build to migrate to {:?}",
+            lockfile.hash_version, CURRENT_HASH_VERSION,
+        ),
+        HashVersion::V1 if proxied => println!(
+            "⚠️ lockfile uses the {:?} shard hash, which stacks every layer on the same shards. run a nuclear build to migrate to {:?}",
+            lockfile.hash_version, CURRENT_HASH_VERSION,
+        ),
+        _ => {}
     }
 
-    let storage = populate_shard_repos(config, &control_repo_path)?;
+    (lockfile, nuke)
+}
 
+// patch treatment -> fingerprint of its series as it is on disk now
+fn patch_fingerprints(
+    config: &config::ExperimentConfig,
+    pinned: Option<&LockFile>,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut fingerprints = HashMap::new();
     for treatment in &config.treatments {
-        let name = match treatment {
-            config::Treatment::Branch(t) => &t.name,
-            config::Treatment::Commit(t) => &t.name,
-            config::Treatment::Patch(t) => &t.name,
+        let config::Treatment::Patch(patch_treatment) = treatment else {
+            continue;
         };
 
-        let shard_ids = match &config.assignment.strategy {
-            config::StrategyType::Random(random) =>
-                shuffled_shards(&random.seed, name, 0, config.shard_count),
+        let fingerprint = patches::fingerprint(&patches::load_series(patch_treatment)?)?;
+        let name = &patch_treatment.name;
 
-            _ => (config.minmax.0..config.minmax.1).collect(),
-        };
+        if pinned.is_some_and(|pinned| pinned.fingerprints.get(name) != Some(&fingerprint)) {
+            return Err(format!("patches of {} don't match the lockfile", name).into());
+        }
 
-        let mut split = 0;
-        if let Some(s) = config.assignment.split.get(name) {
-            split = *s;
-        } else {
-            println!("⚠️ no split for treatment {}, skipping", name);
+        fingerprints.insert(name.clone(), fingerprint);
+    }
+
+    Ok(fingerprints)
+}
+
+// what every local shard needs to end up with its assigned treatments.
+// `lockfile` is updated as if the plan had run up to the point of applying:
+// shards that get reset are forgotten, and so are removed treatments
+fn plan_shards<'a>(
+    config: &'a config::ExperimentConfig,
+    lockfile: &mut LockFile,
+    created: &[usize],
+    commits: &HashMap<String, Oid>,
+    fingerprints: &HashMap<String, String>,
+) -> Result<HashMap<usize, ShardPlan<'a>>, Box<dyn std::error::Error>> {
+    // a shard that had to be recreated starts from the base again
+    for shard in created {
+        forget_shard(lockfile, *shard);
+    }
+
+    let mut plan: HashMap<usize, ShardPlan> = HashMap::new();
+
+    // shards carrying a treatment that resolves to another commit than the
+    // lockfile pins (its ref_ was edited, or the branch moved) get rebuilt
+    // from base, or they'd keep the old commit under the new pin
+    for (name, commit) in commits {
+        let Some(old) = lockfile.resolved.get(name).filter(|old| **old != commit.to_string()).cloned() else {
             continue;
+        };
+
+        let stale = lockfile.applied.get(name).cloned().unwrap_or_default();
+        for shard in stale {
+            println!("🌱 {} moved from {} to {}, rebuilding shard {}", name, old, commit, shard);
+            forget_shard(lockfile, shard);
+            plan.entry(shard).or_default().reset = true;
         }
+    }
 
-        let count = ((shard_ids.len() as f64) * (split as f64 / 100.0)).round() as usize;
-        let iter = shard_ids.iter()
-            .take(count)
-            .skip(
-                lockfile.applied.entry(name.clone()).or_insert(vec![]).len()
-            );
-        for &i in iter {
-            if i < config.minmax.0 || i >= config.minmax.1 {
-                continue;
+    // shards carrying a patch series that changed since get rebuilt from base
+    for (name, fingerprint) in fingerprints {
+        if lockfile.fingerprints.get(name).is_some_and(|old| old != fingerprint) {
+            let stale = lockfile.applied.get(name).cloned().unwrap_or_default();
+            for shard in stale {
+                println!("📝 patches of {} changed, rebuilding shard {}", name, shard);
+                forget_shard(lockfile, shard);
+                plan.entry(shard).or_default().reset = true;
+            }
+        }
+    }
+
+    lockfile.control = control_shards(config, lockfile.hash_version)?;
+    if !lockfile.control.is_empty() {
+        let shards: Vec<String> = lockfile.control.iter().map(|shard| shard.to_string()).collect();
+        println!("🛡️ holding out {} shards as control: {}", shards.len(), shards.join(", "));
+    }
+
+    let assigned = assign_shards(config, lockfile.hash_version)?;
+
+    for shard in config.minmax.0..config.minmax.1 {
+        let target: Vec<&config::Treatment> = config.treatments.iter()
+            .filter(|t| assigned.get(treatment_name(t)).is_some_and(|shards| shards.contains(&shard)))
+            .collect();
+
+        if ramp_down(lockfile, shard, &target) {
+            plan.entry(shard).or_default().reset = true;
+        }
+
+        let current = shard_treatments(lockfile, shard);
+        for treatment in target {
+            if !current.contains(treatment_name(treatment)) {
+                plan.entry(shard).or_default().treatments.push(treatment);
             }
+        }
+    }
+
+    // treatments that were removed from the config are off every shard now
+    lockfile.applied.retain(|name, _| config.treatments.iter().any(|t| treatment_name(t) == name));
+    lockfile.conflicts.retain(|name, _| lockfile.applied.contains_key(name));
+    lockfile.assignment = config.assignment.clone();
+
+    Ok(plan)
+}
+
+fn print_cells(control: &[usize], cells: &[(usize, Vec<String>)]) {
+    println!("🧫 cells:");
+    for (shard, treatments) in cells {
+        let cell = if control.contains(shard) {
+            "control (held out)".to_string()
+        } else if treatments.is_empty() {
+            "control".to_string()
+        } else {
+            treatments.join(" + ")
+        };
+        println!("  shard {}: {}", shard, cell);
+    }
+}
+
+// --locked builds exactly the commits the lockfile pins, even after a nuke.
+// returns the pinned lockfile and base, None for both without --locked
+fn read_pins(
+    config: &config::ExperimentConfig,
+    locked: bool,
+) -> Result<(Option<LockFile>, Option<Oid>), Box<dyn std::error::Error>> {
+    if !locked {
+        return Ok((None, None));
+    }
+
+    let pinned = read_lockfile().map_err(|e| format!("--locked needs a lockfile ({})", e))?;
+    let base_oid = pinned.base_oid.as_ref().ok_or("lockfile doesn't pin the base, build once without --locked")?;
+    let base = Oid::from_str(base_oid)?;
 
-            let shard_repo = storage.get(&i).unwrap();
-            let path = get_home_dir(shard_repo);
+    // patches are pinned by their fingerprint instead
+    for treatment in &config.treatments {
+        let name = treatment_name(treatment);
+        if !pinned.resolved.contains_key(name) && !matches!(treatment, config::Treatment::Patch(_)) {
+            return Err(format!("lockfile doesn't pin treatment {}", name).into());
+        }
+    }
+
+    Ok((Some(pinned), Some(base)))
+}
+
+// everything `build` would do, without touching the disk
+pub fn plan(config: &config::ExperimentConfig, nuclear: bool, locked: bool) -> Result<(), Box<dyn std::error::Error>> {
+    println!("📋 dry run, nothing gets touched");
+
+    let (pinned, pinned_base) = read_pins(config, locked)?;
 
-            println!("💉 applying treatment {} to shard {}", name, i);
-            apply_treatment(&shard_repo, treatment, &path)?;
-            lockfile.applied.entry(name.clone()).or_insert(vec![]).push(i);
+    let (mut lockfile, nuke) = starting_lockfile(config, nuclear);
+    if nuke {
+        let base = pinned_base.map(|pin| pin.to_string()).unwrap_or(config.base.clone());
+        println!("☢️ nuclear build: {} gets wiped and {} cloned at {}", BUILD_DIR, config.repo, base);
+        if config.hooks.control_build.is_some() {
+            println!("🔨 control_build hook runs in the control clone");
+        }
+    } else if let Some(pin) = pinned_base {
+        let base = base_commit_id()?;
+        if pin != base {
+            return Err(format!("control repo is at {} but the lockfile pins {}, rebuild with --nuclear --locked", base, pin).into());
         }
     }
 
-    if config.hooks.build.is_some() || config.templating.is_some() || config.symlinks.is_some() {
-        for i in config.minmax.0..config.minmax.1 {
-            let path = get_home_dir(storage.get(&i).unwrap());
+    let mut created = Vec::new();
+    for shard in config.minmax.0..config.minmax.1 {
+        if nuke || !get_shard_dir(shard)?.exists() {
+            created.push(shard);
+        }
+    }
+
+    // a nuke starts every shard over, nothing can be stale
+    let commits = match nuke {
+        true => HashMap::new(),
+        false => resolve_commits(config, &Repository::open(get_build_dir()?.join(CONTROL_REPO_DIR))?, pinned.as_ref())?,
+    };
+    let fingerprints = patch_fingerprints(config, pinned.as_ref())?;
+    let plan = plan_shards(config, &mut lockfile, &created, &commits, &fingerprints)?;
+
+    let hooks: Vec<&str> = [
+        ("run build hook", config.hooks.build.is_some()),
+        ("fill templates", config.templating.is_some()),
+        ("link symlinks", config.symlinks.is_some()),
+    ].iter().filter(|(_, on)| *on).map(|(step, _)| *step).collect();
+
+    println!("🗺️ shards:");
+    let mut cells = Vec::new();
+    for shard in config.minmax.0..config.minmax.1 {
+        let mut steps = Vec::new();
+        let mut cell = shard_treatments(&lockfile, shard);
+
+        if created.contains(&shard) {
+            steps.push(format!("create ({:?})", config.layout.unwrap_or_default()).to_lowercase());
+        }
 
-            if config.hooks.build.is_some() {
-                println!("🔨 building shard {}", i);
-                utils::run_command_string(
-                    &config.hooks.build.as_ref().unwrap(),
-                    path.to_str().unwrap_or("unknown"),
-                    false,
-                )?;
+        if let Some(shard_plan) = plan.get(&shard) {
+            if shard_plan.reset {
+                steps.push("reset to base".to_string());
             }
 
-            if config.templating.is_some() {
-                println!("📄 filling in config templates for shard {}", i);
-                template_fill(i, config, &path)?;
+            for treatment in &shard_plan.treatments {
+                steps.push(format!("apply {}", treatment_name(treatment)));
+                cell.push(treatment_name(treatment).clone());
             }
+        }
+
+        steps.extend(hooks.iter().map(|step| step.to_string()));
+        println!("  shard {}: {}", shard, if steps.is_empty() { "nothing to do".to_string() } else { steps.join(", ") });
 
-            if let Some(symlinks) = &config.symlinks {
-                for symlink in symlinks {
-                    let path = get_home_dir(storage.get(&i).unwrap());
-                    let symlink_path = path.join(symlink);
+        cell.sort();
+        cells.push((shard, cell));
+    }
 
-                    let default_base = "symlinks/".to_string();
-                    let base = config.symlinks_base.as_ref().unwrap_or(&default_base);
-                    let original_path = config::get_base()?.join(base).join(symlink);
+    print_cells(&lockfile.control, &cells);
 
-                    utils::create_symlink_force(&original_path.to_str().unwrap(), &symlink_path.to_str().unwrap())?;
-                }
+    Ok(())
+}
+
+pub fn build(
+    config: &config::ExperimentConfig,
+    nuclear: bool,
+    jobs: Option<usize>,
+    locked: bool,
+    yes: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = get_build_dir()?;
+    let jobs = jobs.or(config.jobs).unwrap_or(1);
+
+    let control_repo_path = path.join(CONTROL_REPO_DIR);
+
+    let (pinned, pinned_base) = read_pins(config, locked)?;
+
+    let (mut lockfile, nuke) = starting_lockfile(config, nuclear);
+
+    if nuke {
+        // the wipe would take the runner's state file and socket along
+        // while its shards keep running
+        if let Some(state) = runner::running_state()? {
+            return Err(format!("the runner (pid {}) is still up, `bipolar stop` it before a nuclear build", state.pid).into());
+        }
+
+        let existing = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("shard_"))
+            .count();
+
+        let question = format!("wipe {} and its {} shards?", path.display(), existing);
+        if existing > 0 && !yes && !utils::confirm(&question)? {
+            return Err("not wiping the existing build, pass --yes to go ahead".into());
+        }
+
+        println!("☢️ nuclear build triggered");
+
+        std::fs::remove_dir_all(&path)?;
+        clone_control_repo(config, &path, pinned_base)?;
+    }
+
+    let base = base_commit_id()?;
+    if let Some(pin) = pinned_base.filter(|pin| *pin != base) {
+        return Err(format!("control repo is at {} but the lockfile pins {}, rebuild with --nuclear --locked", base, pin).into());
+    }
+    lockfile.base_oid = Some(base.to_string());
+
+    let control_repo = Repository::open(&control_repo_path)?;
+    let commits = resolve_commits(config, &control_repo, pinned.as_ref())?;
+
+    let created = populate_shard_repos(config, &control_repo_path)?;
+    let fingerprints = patch_fingerprints(config, pinned.as_ref())?;
+    let plan = plan_shards(config, &mut lockfile, &created, &commits, &fingerprints)?;
+
+    let shards: Vec<usize> = (config.minmax.0..config.minmax.1).collect();
+    let mut failures: Vec<(usize, String)> = Vec::new();
+
+    if jobs > 1 {
+        println!("🧵 building with {} jobs", jobs);
+    }
+
+    let applied = for_each_shard(&shards, jobs, |shard| {
+        let mut applied = Vec::new();
+        let result = apply_treatments(shard, plan.get(&shard), lockfile.layout, &control_repo_path, base, &commits, &mut applied).map_err(|e| e.to_string());
+
+        (applied, result)
+    });
+
+    for (shard, (names, result)) in applied {
+        for (name, resolution) in names {
+            if let Some(resolution) = resolution {
+                lockfile.conflicts.entry(name.clone()).or_default().push(resolution);
+            }
+            lockfile.applied.entry(name).or_default().push(shard);
+        }
+
+        if let Err(e) = result {
+            failures.push((shard, e));
+        }
+    }
+
+    build_shards(config, &lockfile, &shards, jobs, &mut failures);
+    record_builds(&mut lockfile, &shards, &failures);
+
+    let cells: Vec<(usize, Vec<String>)> = shards.iter().map(|&shard| (shard, shard_treatments(&lockfile, shard))).collect();
+    print_cells(&lockfile.control, &cells);
+
+    lockfile.fingerprints = fingerprints;
+    lockfile.resolved = commits.iter().map(|(name, commit)| (name.clone(), commit.to_string())).collect();
+    write_lockfile(&lockfile)?;
+
+    println!("🔒 lockfile written to {}", get_lockfile_path()?);
+
+    report_failures(&mut failures, shards.len())?;
+    hint_restart(&shards);
+
+    println!("YOU'RE ALL CAUGHT UP :)");
+
+    Ok(())
+}
+
+// brings the remote-tracking branches of a copied shard up to date with the
+// control clone, worktrees share them already
+fn fetch_into_shard(shard_repo: &Repository, control_repo_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut remote = shard_repo.remote_anonymous(control_repo_path.to_str().unwrap())?;
+    remote.fetch(&["+refs/remotes/origin/*:refs/remotes/origin/*"], None, None)?;
+
+    Ok(())
+}
+
+fn update_shard(
+    shard: usize,
+    plan: &ShardPlan,
+    layout: config::ShardLayout,
+    control_repo_path: &Path,
+    base: Oid,
+    commits: &HashMap<String, Oid>,
+    applied: &mut Vec<(String, Option<ConflictResolution>)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = apply_treatments(shard, Some(plan), layout, control_repo_path, base, commits, applied);
+    let shard_repo = Repository::open(get_shard_dir(shard)?)?;
+
+    // leave the shard on base so the next build re-applies it cleanly
+    if result.is_err() {
+        reset_shard(&shard_repo, base)?;
+    }
+
+    result
+}
+
+// fetches the treatment branches and brings only the shards whose
+// treatments moved up to date. fast-forwards get merged on top, anything
+// else (a force push, a changed commit treatment) resets the shard to base
+// and re-applies all of its treatments
+pub fn update(config: &config::ExperimentConfig, jobs: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = jobs.or(config.jobs).unwrap_or(1);
+    let mut lockfile = read_lockfile()
+        .map_err(|e| format!("couldn't read lockfile, did you build? ({})", e))?;
+
+    let control_repo_path = get_build_dir()?.join(CONTROL_REPO_DIR);
+    let control_repo = Repository::open(&control_repo_path)?;
+
+    println!("📡 fetching {}", config.repo);
+    control_repo.find_remote("origin")?.fetch::<&str>(&[], None, None)?;
+
+    let mut commits = HashMap::new();
+    for (name, commit) in &lockfile.resolved {
+        commits.insert(name.clone(), Oid::from_str(commit)?);
+    }
+
+    let mut plan: HashMap<usize, ShardPlan> = HashMap::new();
+    for treatment in &config.treatments {
+        let name = treatment_name(treatment);
+        let Some(new) = resolve_treatment(&control_repo, treatment)? else {
+            continue;
+        };
+
+        let old = commits.insert(name.clone(), new);
+        if old == Some(new) {
+            continue;
+        }
+
+        let fast_forward = match old {
+            Some(old) => control_repo.graph_descendant_of(new, old)?,
+            None => false,
+        };
+
+        match old {
+            Some(old) => println!("🌱 {} moved from {} to {}", name, old, new),
+            None => println!("🌱 {} resolved to {}", name, new),
+        }
+
+        let shards = lockfile.applied.get(name).cloned().unwrap_or_default();
+        for shard in shards {
+            let shard_plan = plan.entry(shard).or_default();
+            shard_plan.reset |= !fast_forward;
+            shard_plan.treatments.push(treatment);
+        }
+    }
+
+    // a reset shard gets everything it had back, with the new commits
+    for (&shard, shard_plan) in plan.iter_mut() {
+        if shard_plan.reset {
+            let current = shard_treatments(&lockfile, shard);
+            shard_plan.treatments = config.treatments.iter()
+                .filter(|t| current.contains(treatment_name(t)))
+                .collect();
+            forget_shard(&mut lockfile, shard);
+        }
+    }
+
+    let mut shards: Vec<usize> = plan.keys().copied().collect();
+    shards.sort();
+
+    if shards.is_empty() {
+        lockfile.resolved = commits.iter().map(|(name, commit)| (name.clone(), commit.to_string())).collect();
+        write_lockfile(&lockfile)?;
+
+        println!("YOU'RE ALL CAUGHT UP :)");
+        return Ok(());
+    }
+
+    let base = base_commit_id()?;
+    let applied = for_each_shard(&shards, jobs, |shard| {
+        let mut applied = Vec::new();
+        let result = update_shard(shard, &plan[&shard], lockfile.layout, &control_repo_path, base, &commits, &mut applied)
+            .map_err(|e| e.to_string());
+
+        (applied, result)
+    });
+
+    let mut failures: Vec<(usize, String)> = Vec::new();
+    for (shard, (names, result)) in applied {
+        if let Err(e) = result {
+            forget_shard(&mut lockfile, shard);
+            failures.push((shard, format!("{}, run `bipolar build` to retry", e)));
+            continue;
+        }
+
+        for (name, resolution) in names {
+            if let Some(resolution) = resolution {
+                lockfile.conflicts.entry(name.clone()).or_default().push(resolution);
+            }
+
+            let applied = lockfile.applied.entry(name).or_default();
+            if !applied.contains(&shard) {
+                applied.push(shard);
             }
         }
-    } else {
-        println!("⚠️ templating config and build hook missing!")
     }
 
+    build_shards(config, &lockfile, &shards, jobs, &mut failures);
+    record_builds(&mut lockfile, &shards, &failures);
+
+    lockfile.resolved = commits.iter().map(|(name, commit)| (name.clone(), commit.to_string())).collect();
     write_lockfile(&lockfile)?;
 
     println!("🔒 lockfile written to {}", get_lockfile_path()?);
+
+    report_failures(&mut failures, shards.len())?;
+    hint_restart(&shards);
+
     println!("YOU'RE ALL CAUGHT UP :)");
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // these pin the shard order experiments are built with. if they break,
+    // running experiments would get reshuffled: add a new HashVersion instead
+    #[test]
+    fn v1_shuffle_is_pinned() {
+        assert_eq!(shuffled_shards(HashVersion::V1, &0, "treatment", 0, 10), vec![8, 5, 2, 1, 0, 6, 3, 7, 9, 4]);
+        assert_eq!(shuffled_shards(HashVersion::V1, &42, "treatment", 0, 10), vec![9, 3, 1, 7, 8, 6, 2, 0, 5, 4]);
+        assert_eq!(shuffled_shards(HashVersion::V1, &42, "new-checkout", 0, 16), vec![5, 9, 2, 14, 13, 10, 0, 4, 12, 3, 7, 11, 1, 15, 8, 6]);
+        assert_eq!(shuffled_shards(HashVersion::V1, &1337, "dark-mode", 4, 12), vec![6, 5, 10, 11, 4, 7, 8, 9]);
+    }
+
+    #[test]
+    fn v1_hash_is_pinned() {
+        assert_eq!(shuffle_hash(HashVersion::V1, &0, ""), 18091345823991644199);
+        assert_eq!(shuffle_hash(HashVersion::V1, &42, "treatment"), 2288783561211734351);
+    }
+
+    #[test]
+    fn shuffle_depends_on_seed_and_name() {
+        let base = shuffled_shards(HashVersion::V1, &42, "treatment", 0, 32);
+
+        assert_ne!(base, shuffled_shards(HashVersion::V1, &43, "treatment", 0, 32));
+        assert_ne!(base, shuffled_shards(HashVersion::V1, &42, "treatment2", 0, 32));
+    }
+
+    pub const PROXY: &str = "[assignment.strategy.Proxy]";
+
+    pub fn random(seed: u64) -> String {
+        format!("[assignment.strategy.Random]\nseed = {}", seed)
+    }
+
+    // `strategy` is its whole table, PROXY or random(seed)
+    pub fn experiment(shard_count: usize, strategy: &str, treatments: &str, split: &str) -> config::ExperimentConfig {
+        toml::from_str(&format!(r#"
+            name = "test"
+            repo = "x"
+            base = "main"
+            shard_count = {shard_count}
+            minmax = [0, {shard_count}]
+            hooks = {{}}
+            {treatments}
+            [assignment.split]
+            {split}
+            {strategy}
+        "#)).unwrap()
+    }
+
+    #[test]
+    fn layers_split_their_pool() {
+        let config = experiment(10, &random(0), r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "c"
+            ref_ = "c"
+        "#, "a = 50\nb = 50\nc = 30");
+
+        let assigned = assign_shards(&config, HashVersion::V1).unwrap();
+        let (a, b) = (&assigned["a"], &assigned["b"]);
+
+        assert_eq!(a.len(), 5);
+        assert_eq!(b.len(), 5);
+        assert!(a.iter().all(|shard| !b.contains(shard)));
+
+        // alone in its layer, so it keeps the shards it had before layers
+        let mut c = shuffled_shards(HashVersion::V1, &0, "c", 0, 10)[..3].to_vec();
+        c.sort();
+        assert_eq!(assigned["c"], c);
+    }
+
+    #[test]
+    fn layer_over_100_is_rejected() {
+        let config = experiment(10, &random(0), r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "checkout"
+        "#, "a = 60\nb = 50");
+
+        assert!(assign_shards(&config, HashVersion::V1).is_err());
+    }
+
+    #[test]
+    fn exclusive_treatments_never_share_a_shard() {
+        // b is exclusive with a and c with b, so all three share one pool,
+        // d is free to overlap with any of them
+        let treatments = r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            exclusive_with = ["a"]
+            [[treatments]]
+            type = "Branch"
+            name = "c"
+            ref_ = "c"
+            layer = "other"
+            exclusive_with = ["b"]
+            [[treatments]]
+            type = "Branch"
+            name = "d"
+            ref_ = "d"
+        "#;
+
+        for (a, b, c) in [(30, 30, 40), (50, 50, 0), (1, 98, 1), (33, 33, 33), (10, 20, 30)] {
+            let split = format!("a = {}\nb = {}\nc = {}\nd = 50", a, b, c);
+
+            for shard_count in 1..40 {
+                for seed in 0..25 {
+                    let config = experiment(shard_count, &random(seed), treatments, &split);
+                    let assigned = assign_shards(&config, HashVersion::V1).unwrap();
+
+                    for (x, y) in [("a", "b"), ("a", "c"), ("b", "c")] {
+                        assert!(
+                            assigned[x].iter().all(|shard| !assigned[y].contains(shard)),
+                            "{} and {} overlap with {} shards, seed {}", x, y, shard_count, seed,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exclusive_group_over_100_is_rejected() {
+        let config = experiment(10, &random(0), r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            exclusive_with = ["a"]
+        "#, "a = 60\nb = 50");
+
+        assert!(assign_shards(&config, HashVersion::V1).is_err());
+    }
+
+    #[test]
+    fn control_is_held_out_from_treatments() {
+        let treatments = r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "other"
+        "#;
+
+        for seed in 0..25 {
+            let mut config = experiment(20, &random(seed), treatments, "a = 100\nb = 50");
+            config.assignment.control = Some(25);
+
+            let control = control_shards(&config, HashVersion::V1).unwrap();
+            let assigned = assign_shards(&config, HashVersion::V1).unwrap();
+
+            assert_eq!(control.len(), 5);
+            assert_eq!(assigned["a"].len(), 15);
+            assert!(assigned.values().flatten().all(|shard| !control.contains(shard)));
+        }
+    }
+
+    #[test]
+    fn proxy_layers_cross_instead_of_stacking() {
+        let config = experiment(20, PROXY, r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            layer = "x"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "y"
+        "#, "a = 50\nb = 50");
+
+        let assigned = assign_shards(&config, HashVersion::V2).unwrap();
+        for (a, b) in [(true, true), (true, false), (false, true), (false, false)] {
+            let cell = (0..20).filter(|shard| assigned["a"].contains(shard) == a && assigned["b"].contains(shard) == b);
+            assert!(cell.count() > 0, "no shard gets a: {}, b: {}", a, b);
+        }
+
+        // lockfiles from before keep their layout
+        let assigned = assign_shards(&config, HashVersion::V1).unwrap();
+        assert_eq!(assigned["a"], assigned["b"]);
+    }
+
+    #[test]
+    fn ramping_down_resets_only_shards_that_lose_a_treatment() {
+        let config = experiment(4, &random(0), r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+        "#, "a = 50\nb = 50");
+        let (a, b) = (&config.treatments[0], &config.treatments[1]);
+
+        let mut lockfile = form_lockfile(&config);
+        lockfile.applied.insert("a".to_string(), vec![0, 1]);
+        lockfile.applied.insert("b".to_string(), vec![1, 2]);
+
+        // keeps everything it has
+        assert!(!ramp_down(&mut lockfile, 0, &[a]));
+        // gains one, that's not a ramp down
+        assert!(!ramp_down(&mut lockfile, 2, &[a, b]));
+        // loses b, keeps a. it starts over from base so a comes off too
+        assert!(ramp_down(&mut lockfile, 1, &[a]));
+
+        assert_eq!(lockfile.applied["a"], vec![0]);
+        assert_eq!(lockfile.applied["b"], vec![2]);
+    }
+
+    #[test]
+    fn treatments_that_moved_get_rebuilt() {
+        let config = experiment(4, &random(0), r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+        "#, "a = 100");
+
+        let (old, new) = (Oid::from_str(&"1".repeat(40)).unwrap(), Oid::from_str(&"2".repeat(40)).unwrap());
+        let mut lockfile = form_lockfile(&config);
+        lockfile.applied.insert("a".to_string(), vec![0, 1, 2, 3]);
+        lockfile.resolved.insert("a".to_string(), old.to_string());
+
+        let commits = HashMap::from([("a".to_string(), old)]);
+        let plan = plan_shards(&config, &mut lockfile, &[], &commits, &HashMap::new()).unwrap();
+        assert!(plan.is_empty());
+
+        let commits = HashMap::from([("a".to_string(), new)]);
+        let plan = plan_shards(&config, &mut lockfile, &[], &commits, &HashMap::new()).unwrap();
+        for shard in 0..4 {
+            assert!(plan[&shard].reset);
+            assert_eq!(plan[&shard].treatments.len(), 1);
+        }
+        assert!(lockfile.applied["a"].is_empty());
+    }
+
+    #[test]
+    fn lockfile_without_hash_version_is_legacy() {
+        let lockfile: LockFile = toml::from_str(r#"
+            base = "main"
+            repo = "x"
+            shard_count = 2
+            minmax = [0, 2]
+            [assignment.split]
+            [assignment.strategy.Random]
+            seed = 0
+            [applied]
+        "#).unwrap();
+
+        assert_eq!(lockfile.hash_version, HashVersion::Legacy);
+    }
+}
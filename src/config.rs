@@ -17,6 +17,12 @@ pub struct ExperimentConfig {
     pub symlinks: Option<Vec<String>>,
     pub symlinks_base: Option<String>,
     pub environment: Option<HashMap<String, String>>,
+    pub ports: Option<Ports>,
+    pub supervisor: Option<Supervisor>,
+    pub health: Option<HealthCheck>,
+    pub layout: Option<ShardLayout>,
+    // how many shards `bipolar build` works on at once
+    pub jobs: Option<usize>,
 
     // if we have multiple servers, we can configure each instance of
     // bipolar to have a minimum and maximum number of shards, but the
@@ -25,6 +31,85 @@ pub struct ExperimentConfig {
     pub minmax: (usize, usize),
 }
 
+// how shard checkouts are made from the control clone. `copy` duplicates
+// the whole clone (build artifacts of `control_build` included), `worktree`
+// adds a git worktree per shard that shares the control clone's objects
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShardLayout {
+    #[default]
+    Copy,
+    Worktree,
+}
+
+// every shard listens on `base + shard` unless it has an entry in `map`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ports {
+    pub base: Option<u16>,
+    pub map: Option<HashMap<String, u16>>,
+}
+
+impl ExperimentConfig {
+    pub fn shard_port(&self, shard: usize) -> Option<u16> {
+        let ports = self.ports.as_ref()?;
+
+        if let Some(port) = ports.map.as_ref().and_then(|map| map.get(&shard.to_string())) {
+            return Some(*port);
+        }
+
+        let port = ports.base? as usize + shard;
+        u16::try_from(port).ok()
+    }
+
+    // where the shard serves: its upstream under Proxy, else its port locally
+    pub fn shard_address(&self, shard: usize) -> Option<String> {
+        let upstream = match &self.assignment.strategy {
+            StrategyType::Proxy(proxy) => proxy.upstreams.as_ref().and_then(|upstreams| upstreams.get(&shard.to_string())),
+            _ => None,
+        };
+
+        upstream.cloned().or(self.shard_port(shard).map(|port| format!("127.0.0.1:{}", port)))
+    }
+}
+
+// how `bipolar run` restarts shards that exited. the backoff doubles after
+// every crash up to `max_backoff_ms`, and a shard that crashes more than
+// `max_restarts` times within `crash_window_secs` is given up on.
+// on shutdown shards get SIGTERM and `grace_period_ms` before SIGKILL
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Supervisor {
+    pub backoff_ms: Option<u64>,
+    pub max_backoff_ms: Option<u64>,
+    pub max_restarts: Option<usize>,
+    pub crash_window_secs: Option<u64>,
+    pub grace_period_ms: Option<u64>,
+}
+
+// how `bipolar run` tells a shard is up. a shard is healthy from its first
+// passing check until `threshold` checks in a row fail. the runner waits up
+// to `startup_timeout_secs` for every shard to be healthy before calling the
+// experiment started, and the proxy only routes to healthy shards
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HealthCheck {
+    pub probe: Probe,
+    pub interval_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub threshold: Option<u32>,
+    pub startup_timeout_secs: Option<u64>,
+}
+
+// `Http` and `Tcp` go to the shard's address, the same one the proxy uses:
+// its entry in the Proxy `upstreams`, else its port on localhost
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum Probe {
+    // passes on a 2xx or 3xx response
+    Http { path: Option<String> },
+    Tcp,
+    // runs in the shard's directory with its environment, passes on exit 0
+    Command { command: String },
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Templating {
     pub path: String,
@@ -38,8 +123,25 @@ pub struct Hooks {
     pub run: Option<String>,
 }
 
+// where the proxy takes the user identity from, it's hashed together with
+// the experiment name so the same user always lands on the same shard
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum IdentitySource {
+    Cookie { name: String },
+    Header { name: String },
+    Ip,
+}
+
+// traffic is split at request time by `bipolar proxy`
 #[derive(Debug, Deserialize, Serialize)]
-pub struct DefaultStrategy {}
+pub struct DefaultStrategy {
+    pub listen: Option<String>,
+    // shard index -> upstream address, e.g. "0" = "127.0.0.1:3000".
+    // shards without an entry are reached on their allocated port
+    pub upstreams: Option<HashMap<String, String>>,
+    pub identity: Option<IdentitySource>,
+}
 
 // assigned on build time
 #[derive(Debug, Deserialize, Serialize)]
@@ -56,7 +158,11 @@ pub enum StrategyType {
 impl StrategyType {
     pub fn clone(&self) -> StrategyType {
         match self {
-            StrategyType::Proxy(_) => StrategyType::Proxy(DefaultStrategy {}),
+            StrategyType::Proxy(proxy) => StrategyType::Proxy(DefaultStrategy {
+                listen: proxy.listen.clone(),
+                upstreams: proxy.upstreams.clone(),
+                identity: proxy.identity.clone(),
+            }),
             StrategyType::Random(random) => StrategyType::Random(RandomStrategy {
                 seed: random.seed,
             }),
@@ -66,8 +172,12 @@ impl StrategyType {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Assignment {
+    // percentages of the shards left over after the `control` holdout
     pub split: HashMap<String, u8>,
     pub strategy: StrategyType,
+    // percentage of shards held out before any treatment is drawn, they
+    // always run the base
+    pub control: Option<u8>,
 }
 
 impl Assignment {
@@ -75,28 +185,60 @@ impl Assignment {
         Assignment {
             split: self.split.clone(),
             strategy: self.strategy.clone(),
+            control: self.control,
         }
     }
 }
 
+// what to do with paths both the base and a treatment changed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // fail the shard and list the conflicted paths
+    #[default]
+    Fail,
+    // take the base version
+    Ours,
+    // take the treatment version
+    Theirs,
+    // commit the files with conflict markers in them
+    KeepMarkers,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BranchTreatment {
     pub name: String,
     pub ref_: String,
+    pub on_conflict: Option<ConflictPolicy>,
+    pub layer: Option<String>,
+    pub exclusive_with: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CommitTreatment {
     pub name: String,
     pub ref_: String,
+    pub on_conflict: Option<ConflictPolicy>,
+    pub layer: Option<String>,
+    pub exclusive_with: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PatchTreatment {
     pub name: String,
-    pub patch: String,
+    pub patch: Option<String>,
+    // applied in order after `patch`
+    pub patches: Option<Vec<String>>,
+    pub layer: Option<String>,
+    pub exclusive_with: Option<Vec<String>>,
 }
 
+// treatments that share a `layer` never land on the same shard, they split
+// the layer's shards between them. treatments in different layers are
+// assigned independently and combine into cells. a treatment without a
+// layer is alone in a layer named after itself.
+// `exclusive_with` names treatments that must never share a shard with this
+// one, their layers get merged into a single pool
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Treatment {
@@ -157,10 +299,16 @@ pub fn init_config(name: Option<String>) -> Result<(), Box<dyn std::error::Error
         assignment: Assignment {
             split: HashMap::new(),
             strategy: StrategyType::Random(RandomStrategy { seed: 0 }),
+            control: None,
         },
         symlinks: None,
         symlinks_base: None,
         environment: None,
+        ports: None,
+        supervisor: None,
+        health: None,
+        layout: None,
+        jobs: None,
         shard_count: 1,
         minmax: (0, 0),
     };
@@ -183,7 +331,6 @@ pub fn add_self_to_gitignore() -> Result<(), Box<dyn std::error::Error>> {
 
     if !contents.contains(COMMENT) {
         let mut file = OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
             .open(path)?;
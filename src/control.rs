@@ -0,0 +1,152 @@
+use crate::{build, health};
+use serde::{Serialize, Deserialize};
+use std::{path::PathBuf, sync::mpsc};
+
+// unix socket of a running `bipolar run`. every line written to it is a
+// JSON request and gets a JSON response line back, e.g.
+// {"command": "restart", "shard": 2} -> {"ok": true}
+pub const SOCKET_FILE: &str = "runner.sock";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    List,
+    // every shard when `shard` is left out
+    Restart { shard: Option<usize> },
+    Stop { shard: usize },
+    // re-reads the config and the lockfile for shards started from now on
+    Reload,
+    Stats,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shards: Option<Vec<ShardInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
+}
+
+impl Response {
+    pub fn ok() -> Response {
+        Response { ok: true, ..Default::default() }
+    }
+
+    pub fn error(error: impl ToString) -> Response {
+        Response { ok: false, error: Some(error.to_string()), ..Default::default() }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShardInfo {
+    pub shard: usize,
+    // running, stopping, backing off, stopped or given up
+    pub state: String,
+    pub pid: Option<u32>,
+    pub restarts: usize,
+    pub uptime_secs: Option<u64>,
+    // None while it isn't running, or without a health check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<health::Health>,
+    // finish time of the build it was started from, as unix seconds
+    pub built_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub shards: usize,
+    pub running: usize,
+    pub restarts: usize,
+    pub given_up: usize,
+}
+
+// a request and where its response goes
+pub type Pending = (Request, mpsc::Sender<Response>);
+
+pub fn get_socket_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(build::get_build_dir()?.join(SOCKET_FILE))
+}
+
+// accepts connections on background threads. requests come out of the
+// returned receiver, whoever handles them answers through the sender
+#[cfg(unix)]
+pub fn listen() -> Result<mpsc::Receiver<Pending>, Box<dyn std::error::Error>> {
+    use std::{fs, os::unix::net::UnixListener, thread};
+
+    let path = get_socket_path()?;
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    let (requests, pending) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+
+            let requests = requests.clone();
+            thread::spawn(move || {
+                let _ = serve(stream, &requests);
+            });
+        }
+    });
+
+    Ok(pending)
+}
+
+#[cfg(unix)]
+fn serve(stream: std::os::unix::net::UnixStream, requests: &mpsc::Sender<Pending>) -> std::io::Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let (reply, response) = mpsc::channel();
+                requests.send((request, reply)).ok()
+                    .and_then(|_| response.recv().ok())
+                    .unwrap_or(Response::error("runner is shutting down"))
+            }
+            Err(e) => Response::error(format!("bad request: {}", e)),
+        };
+
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+
+    Ok(())
+}
+
+// sends one request to the running runner, a response with an error
+// becomes an Err
+#[cfg(unix)]
+pub fn send(request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+    use std::{io::{BufRead, BufReader, Write}, os::unix::net::UnixStream};
+
+    let mut stream = UnixStream::connect(get_socket_path()?)
+        .map_err(|e| format!("couldn't reach the runner, is it running? ({})", e))?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    let response: Response = serde_json::from_str(&line)?;
+    match response.error {
+        Some(error) => Err(error.into()),
+        None => Ok(response),
+    }
+}
+
+#[cfg(windows)]
+pub fn send(_request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+    Err("the control socket needs unix".into())
+}
@@ -0,0 +1,193 @@
+use crate::{build, config, utils};
+use serde::{Serialize, Deserialize};
+use std::{io::{BufRead, BufReader, Write}, net::{TcpStream, ToSocketAddrs}, path::PathBuf, process::Stdio, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_THRESHOLD: u32 = 3;
+const DEFAULT_STARTUP_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    // hasn't passed a check since it was (re)started
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+pub struct Settings {
+    pub probe: config::Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub threshold: u32,
+    pub startup_timeout: Duration,
+}
+
+impl Settings {
+    // None without a health check, shards are taken as up once they're spawned
+    pub fn from_config(config: &config::ExperimentConfig) -> Option<Settings> {
+        let health = config.health.as_ref()?;
+
+        Some(Settings {
+            probe: health.probe.clone(),
+            interval: Duration::from_millis(health.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS)),
+            timeout: Duration::from_millis(health.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+            threshold: health.threshold.unwrap_or(DEFAULT_THRESHOLD).max(1),
+            startup_timeout: Duration::from_secs(health.startup_timeout_secs.unwrap_or(DEFAULT_STARTUP_TIMEOUT_SECS)),
+        })
+    }
+}
+
+// everything a probe needs, owned so it can run on its own thread
+struct Target {
+    // same as the proxy's upstream for the shard
+    address: Option<String>,
+    dir: PathBuf,
+    env: Vec<(String, String)>,
+}
+
+fn connect(address: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let resolved = address.to_socket_addrs()
+        .map_err(|e| format!("couldn't resolve {}: {}", address, e))?
+        .next()
+        .ok_or(format!("{} didn't resolve to anything", address))?;
+
+    TcpStream::connect_timeout(&resolved, timeout)
+        .map_err(|e| format!("couldn't connect to {}: {}", address, e))
+}
+
+fn probe_http(path: &str, address: &str, timeout: Duration) -> Result<(), String> {
+    let mut stream = connect(address, timeout)?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address)
+        .map_err(|e| format!("couldn't send request: {}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)
+        .map_err(|e| format!("no response to GET {}: {}", path, e))?;
+
+    match line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') || status.starts_with('3') => Ok(()),
+        Some(status) => Err(format!("GET {} returned {}", path, status)),
+        None => Err(format!("GET {} got no status line", path)),
+    }
+}
+
+fn probe_command(command: &str, target: &Target, timeout: Duration) -> Result<(), String> {
+    let mut cmd = utils::shell_command(command, &target.dir, &target.env);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // so a timed out check takes whatever it spawned along
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let mut child = cmd.spawn().map_err(|e| format!("couldn't run `{}`: {}", command, e))?;
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("`{}` exited with {}", command, status)),
+            Ok(None) if Instant::now() >= deadline => {
+                utils::signal_group(&mut child, true);
+                let _ = child.wait();
+                return Err(format!("`{}` timed out after {:?}", command, timeout));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+fn probe(probe: &config::Probe, target: &Target, timeout: Duration) -> Result<(), String> {
+    let address = || target.address.as_deref().ok_or("shard has no upstream or port configured".to_string());
+
+    match probe {
+        config::Probe::Http { path } => probe_http(path.as_deref().unwrap_or("/"), address()?, timeout),
+        config::Probe::Tcp => connect(address()?, timeout).map(|_| ()),
+        config::Probe::Command { command } => probe_command(command, target, timeout),
+    }
+}
+
+// health of one shard. probes run on their own thread so a slow one never
+// holds up the runner, at most one at a time
+pub struct Checker {
+    pub health: Health,
+    pub last_error: Option<String>,
+    failures: u32,
+    next_at: Instant,
+    pending: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker {
+            health: Health::Starting,
+            last_error: None,
+            failures: 0,
+            next_at: Instant::now(),
+            pending: None,
+        }
+    }
+
+    // the shard's process was replaced, whatever we knew is stale
+    pub fn reset(&mut self) {
+        *self = Checker::new();
+    }
+
+    // starts a probe when one is due and collects a finished one, returns the
+    // new health when it changed
+    pub fn poll(
+        &mut self,
+        settings: &Settings,
+        config: &config::ExperimentConfig,
+        lockfile: &build::LockFile,
+        shard: usize,
+    ) -> Option<Health> {
+        if let Some(pending) = self.pending.take_if(|pending| pending.is_finished()) {
+            let result = pending.join().unwrap_or(Err("health check panicked".to_string()));
+
+            let before = self.health;
+            match result {
+                Ok(()) => {
+                    self.failures = 0;
+                    self.last_error = None;
+                    self.health = Health::Healthy;
+                }
+                Err(e) => {
+                    self.failures += 1;
+                    self.last_error = Some(e);
+                    if self.health == Health::Healthy && self.failures >= settings.threshold {
+                        self.health = Health::Unhealthy;
+                    }
+                }
+            }
+
+            if self.health != before {
+                return Some(self.health);
+            }
+        }
+
+        if self.pending.is_none() && Instant::now() >= self.next_at {
+            let target = Target {
+                address: config.shard_address(shard),
+                dir: build::get_shard_dir(shard).unwrap_or_default(),
+                env: build::shard_env(config, lockfile, shard),
+            };
+            let (check, timeout) = (settings.probe.clone(), settings.timeout);
+
+            self.next_at = Instant::now() + settings.interval;
+            self.pending = Some(thread::spawn(move || probe(&check, &target, timeout)));
+        }
+
+        None
+    }
+}
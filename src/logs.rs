@@ -0,0 +1,243 @@
+use crate::{build, config};
+use std::{fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, process::Child, sync::{Arc, Mutex}, thread::{self, JoinHandle}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+pub const LOGS_DIR: &str = "logs";
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+const KEEP_ROTATED: usize = 3;
+
+// where a child's output goes: a log file on disk, and the console with
+// `prefix` in front of every line
+pub struct LogTarget {
+    pub path: PathBuf,
+    pub prefix: String,
+}
+
+pub fn get_log_path(shard: usize, kind: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = build::get_build_dir()?;
+    path.push(LOGS_DIR);
+    path.push(format!("shard_{}", shard));
+    path.push(format!("{}.log", kind));
+
+    Ok(path)
+}
+
+pub fn control_log_target() -> Result<LogTarget, Box<dyn std::error::Error>> {
+    let mut path = build::get_build_dir()?;
+    path.push(LOGS_DIR);
+    path.push("control");
+    path.push("build.log");
+
+    Ok(LogTarget { path, prefix: "[control]".to_string() })
+}
+
+// output of a `bipolar run --detach`
+pub fn runner_log_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = build::get_build_dir()?;
+    path.push(LOGS_DIR);
+    path.push("runner.log");
+
+    Ok(path)
+}
+
+pub fn shard_log_target(
+    lockfile: &build::LockFile,
+    shard: usize,
+    kind: &str,
+) -> Result<LogTarget, Box<dyn std::error::Error>> {
+    let treatments = build::shard_treatments(lockfile, shard);
+    let label = if treatments.is_empty() { "control".to_string() } else { treatments.join("+") };
+
+    Ok(LogTarget {
+        path: get_log_path(shard, kind)?,
+        prefix: format!("[shard {} {}]", shard, label),
+    })
+}
+
+struct LogWriter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl LogWriter {
+    fn open(path: &Path) -> io::Result<LogWriter> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogWriter { path: path.to_path_buf(), file, size })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    // shard.log -> shard.log.1 -> ... -> shard.log.KEEP_ROTATED, oldest dropped
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP_ROTATED).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+
+        fs::rename(&self.path, self.rotated(1))?;
+        *self = LogWriter::open(&self.path)?;
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+}
+
+fn pump<R: Read + Send + 'static>(
+    source: R,
+    writer: Arc<Mutex<LogWriter>>,
+    prefix: String,
+    stderr: bool,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(source);
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if let Ok(mut writer) = writer.lock() {
+                let _ = writer.write_line(line);
+            }
+
+            if stderr {
+                let _ = writeln!(io::stderr(), "{} {}", prefix, line);
+            } else {
+                let _ = writeln!(io::stdout(), "{} {}", prefix, line);
+            }
+        }
+    })
+}
+
+// child must have been spawned with piped stdout and stderr. the returned
+// handles finish once the child closes both
+pub fn capture(child: &mut Child, target: &LogTarget) -> io::Result<Vec<JoinHandle<()>>> {
+    let mut writer = LogWriter::open(&target.path)?;
+
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    writer.write_line(&format!("=== started at {} (unix) ===", started))?;
+
+    let writer = Arc::new(Mutex::new(writer));
+    let mut handles = Vec::new();
+
+    if let Some(stdout) = child.stdout.take() {
+        handles.push(pump(stdout, writer.clone(), target.prefix.clone(), false));
+    }
+
+    if let Some(stderr) = child.stderr.take() {
+        handles.push(pump(stderr, writer, target.prefix.clone(), true));
+    }
+
+    Ok(handles)
+}
+
+struct Tail {
+    path: PathBuf,
+    prefix: String,
+    offset: u64,
+    partial: String,
+}
+
+impl Tail {
+    // prints whatever was appended since the last call, starting over when
+    // the file got rotated underneath us
+    fn poll(&mut self) -> io::Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if file.metadata()?.len() < self.offset {
+            self.offset = 0;
+            self.partial.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.offset))?;
+
+        let mut buf = Vec::new();
+        self.offset += file.read_to_end(&mut buf)? as u64;
+        self.partial.push_str(&String::from_utf8_lossy(&buf));
+
+        let mut out = io::stdout().lock();
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            writeln!(out, "{} {}", self.prefix, line.trim_end_matches(['\r', '\n']))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn logs(
+    config: &config::ExperimentConfig,
+    shard: Option<usize>,
+    build: bool,
+    follow: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let kind = if build { "build" } else { "run" };
+    let shards: Vec<usize> = match shard {
+        Some(shard) => vec![shard],
+        None => (config.minmax.0..config.minmax.1).collect(),
+    };
+
+    let mut tails = Vec::new();
+    for shard in shards {
+        let path = get_log_path(shard, kind)?;
+        if !path.exists() && !follow {
+            println!("⚠️ no {} log for shard {} yet", kind, shard);
+            continue;
+        }
+
+        tails.push(Tail {
+            path,
+            prefix: format!("[shard {}]", shard),
+            offset: 0,
+            partial: String::new(),
+        });
+    }
+
+    loop {
+        for tail in &mut tails {
+            match tail.poll() {
+                // e.g. piped into `head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                result => result?,
+            }
+        }
+
+        if !follow {
+            return Ok(());
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+}
@@ -1,6 +1,12 @@
 mod config;
+mod control;
 mod build;
+mod health;
+mod logs;
+mod patches;
+mod proxy;
 mod runner;
+mod status;
 mod utils;
 
 use clap::{Parser, Subcommand};
@@ -23,9 +29,81 @@ enum Commands {
     Build {
         #[arg(short, long)]
         nuclear: bool,
+
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        // fail instead of building anything the lockfile doesn't pin
+        #[arg(long)]
+        locked: bool,
+
+        // only print what would happen, same as `bipolar plan`
+        #[arg(long)]
+        dry_run: bool,
+
+        // wipe an existing build without asking
+        #[arg(short, long)]
+        yes: bool,
     },
 
-    Run,
+    Plan {
+        #[arg(short, long)]
+        nuclear: bool,
+
+        // plan with the commits the lockfile pins, failing where build --locked would
+        #[arg(long)]
+        locked: bool,
+    },
+
+    Update {
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+
+    Run {
+        #[arg(short, long)]
+        detach: bool,
+    },
+
+    Stop {
+        // only this shard, the runner keeps going
+        #[arg(short, long)]
+        shard: Option<usize>,
+    },
+
+    Restart {
+        #[arg(short, long, conflicts_with = "rolling")]
+        shard: Option<usize>,
+
+        // only the shards rebuilt since they started, a batch at a time
+        #[arg(short, long)]
+        rolling: bool,
+
+        // how many shards a rolling restart takes down at once
+        #[arg(short, long, default_value_t = 1, requires = "rolling")]
+        batch: usize,
+    },
+
+    Proxy {
+        #[arg(short, long)]
+        listen: Option<String>,
+    },
+
+    Status {
+        #[arg(long)]
+        json: bool,
+    },
+
+    Logs {
+        #[arg(short, long)]
+        shard: Option<usize>,
+
+        #[arg(short, long)]
+        follow: bool,
+
+        #[arg(short, long)]
+        build: bool,
+    },
 }
 
 fn main() {
@@ -44,20 +122,91 @@ fn main() {
             }
         },
 
-        Commands::Build { nuclear } => {
+        Commands::Build { nuclear, jobs, locked, dry_run, yes } => {
             let config = config::try_load_config();
-            if let Err(e) = build::build(&config, nuclear) {
+            let result = match dry_run {
+                true => build::plan(&config, nuclear, locked),
+                false => build::build(&config, nuclear, jobs, locked, yes),
+            };
+
+            if let Err(e) = result {
                 eprintln!("error building: {}", e);
                 std::process::exit(1);
             }
         },
 
-        Commands::Run => {
+        Commands::Plan { nuclear, locked } => {
             let config = config::try_load_config();
-            if let Err(e) = runner::run(&config) {
+            if let Err(e) = build::plan(&config, nuclear, locked) {
+                eprintln!("error planning: {}", e);
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Update { jobs } => {
+            let config = config::try_load_config();
+            if let Err(e) = build::update(&config, jobs) {
+                eprintln!("error updating: {}", e);
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Run { detach } => {
+            let config = config::try_load_config();
+            if let Err(e) = runner::run(config, detach) {
                 eprintln!("error running: {}", e);
                 std::process::exit(1);
             }
         },
+
+        Commands::Stop { shard } => {
+            let config = config::try_load_config();
+            let result = match shard {
+                Some(shard) => runner::stop_shard(shard),
+                None => runner::stop(&config),
+            };
+
+            if let Err(e) = result {
+                eprintln!("error stopping: {}", e);
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Restart { shard, rolling, batch } => {
+            let config = config::try_load_config();
+            let result = match rolling {
+                true => runner::rolling_restart(&config, batch),
+                false => runner::restart(shard),
+            };
+
+            if let Err(e) = result {
+                eprintln!("error restarting: {}", e);
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Proxy { listen } => {
+            let config = config::try_load_config();
+            if let Err(e) = proxy::proxy(&config, listen) {
+                eprintln!("error proxying: {}", e);
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Status { json } => {
+            let config = config::try_load_config();
+            if let Err(e) = status::status(&config, json) {
+                eprintln!("error getting status: {}", e);
+                std::process::exit(1);
+            }
+        },
+
+        Commands::Logs { shard, follow, build } => {
+            let config = config::try_load_config();
+            if let Err(e) = logs::logs(&config, shard, build, follow) {
+                eprintln!("error reading logs: {}", e);
+                std::process::exit(1);
+            }
+        },
     }
 }
@@ -0,0 +1,240 @@
+use crate::config;
+use git2::{ObjectType, Oid};
+use std::{fs, path::Path};
+
+pub struct Patch {
+    pub label: String,
+    pub contents: Vec<u8>,
+}
+
+// `git format-patch` separates messages in an mbox with lines like
+// "From 1234abcd... Mon Sep 17 00:00:00 2001"
+fn is_mbox_separator(line: &str) -> bool {
+    let Some(rest) = line.strip_prefix("From ") else {
+        return false;
+    };
+
+    let hash = rest.split(' ').next().unwrap_or("");
+    hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// how many old and new lines the hunk under "@@ -1,3 +1,4 @@" spans
+fn hunk_lengths(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ ")?.split(' ');
+    let length = |range: Option<&str>, sign: char| -> Option<usize> {
+        match range?.strip_prefix(sign)?.split_once(',') {
+            Some((_, length)) => length.parse().ok(),
+            None => Some(1),
+        }
+    };
+
+    Some((length(ranges.next(), '-')?, length(ranges.next(), '+')?))
+}
+
+// strips the mail headers, commit message and signature around the diff,
+// plain diffs are left alone. the signature starts at a "-- " line, but
+// only between hunks: inside one that's a removed "- " line
+fn extract_diff(message: &str) -> String {
+    let Some(start) = message.find("diff --git ") else {
+        return message.to_string();
+    };
+
+    let mut diff = String::new();
+    let (mut old, mut new) = (0, 0);
+    for line in message[start..].split_inclusive('\n') {
+        if old == 0 && new == 0 {
+            if line.trim_end_matches(['\r', '\n']) == "-- " {
+                break;
+            }
+            if let Some(lengths) = hunk_lengths(line) {
+                (old, new) = lengths;
+            }
+        } else {
+            match line.as_bytes()[0] {
+                b'-' => old -= old.min(1),
+                b'+' => new -= new.min(1),
+                // "\ No newline at end of file"
+                b'\\' => {}
+                // context, some editors strip the space off empty ones
+                _ => {
+                    old -= old.min(1);
+                    new -= new.min(1);
+                }
+            }
+        }
+
+        diff.push_str(line);
+    }
+
+    diff
+}
+
+fn split_file(path: &Path, label: &str) -> Result<Vec<Patch>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read patch {}: {}", path.display(), e))?;
+
+    let mut messages: Vec<String> = Vec::new();
+    for line in contents.split_inclusive('\n') {
+        if is_mbox_separator(line) || messages.is_empty() {
+            messages.push(String::new());
+        }
+        messages.last_mut().unwrap().push_str(line);
+    }
+
+    let messages: Vec<String> = messages.into_iter()
+        .filter(|message| !message.trim().is_empty())
+        .collect();
+
+    let count = messages.len();
+    Ok(messages.into_iter().enumerate().map(|(i, message)| Patch {
+        label: if count > 1 { format!("{}#{}", label, i + 1) } else { label.to_string() },
+        contents: extract_diff(&message).into_bytes(),
+    }).collect())
+}
+
+// every entry is a patch file, an mbox, or a directory of those (applied in
+// file name order). paths are relative to the project
+pub fn load_series(treatment: &config::PatchTreatment) -> Result<Vec<Patch>, Box<dyn std::error::Error>> {
+    let base = config::get_base()?;
+
+    let entries: Vec<&String> = treatment.patch.iter()
+        .chain(treatment.patches.iter().flatten())
+        .collect();
+
+    if entries.is_empty() {
+        return Err(format!("patch treatment {} has no patch or patches", treatment.name).into());
+    }
+
+    let mut series = Vec::new();
+    for entry in entries {
+        let path = base.join(entry);
+
+        if path.is_dir() {
+            let mut files: Vec<_> = fs::read_dir(&path)?
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|ext| ext == "patch" || ext == "diff"))
+                .collect();
+            files.sort();
+
+            for file in files {
+                let label = file.strip_prefix(&base).unwrap_or(&file).display().to_string();
+                series.extend(split_file(&file, &label)?);
+            }
+        } else {
+            series.extend(split_file(&path, entry)?);
+        }
+    }
+
+    Ok(series)
+}
+
+// changes whenever any patch in the series, or their order, changes
+pub fn fingerprint(series: &[Patch]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut buf = Vec::new();
+    for patch in series {
+        buf.extend(&patch.contents);
+        buf.push(0);
+    }
+
+    Ok(Oid::hash_object(ObjectType::Blob, &buf)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MBOX: &str = "\
+From 1111111111111111111111111111111111111111 Mon Sep 17 00:00:00 2001
+From: Someone <someone@example.com>
+Subject: [PATCH 1/2] drop the empty bullet
+
+---
+ README.md | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+diff --git a/README.md b/README.md
+index 1234567..89abcde 100644
+--- a/README.md
++++ b/README.md
+@@ -1,3 +1,3 @@
+ # readme
+-- 
++- item
+ end
+-- 
+2.43.0
+
+From 2222222222222222222222222222222222222222 Mon Sep 17 00:00:00 2001
+From: Someone <someone@example.com>
+Subject: [PATCH 2/2] add a line
+
+diff --git a/a.txt b/a.txt
+index 1234567..89abcde 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1,2 @@
+ a
++b
+-- 
+2.43.0
+";
+
+    const FIRST: &str = "\
+diff --git a/README.md b/README.md
+index 1234567..89abcde 100644
+--- a/README.md
++++ b/README.md
+@@ -1,3 +1,3 @@
+ # readme
+-- 
++- item
+ end
+";
+
+    const SECOND: &str = "\
+diff --git a/a.txt b/a.txt
+index 1234567..89abcde 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1,2 @@
+ a
++b
+";
+
+    #[test]
+    fn signature_is_stripped_but_removed_bullets_are_kept() {
+        let first = &MBOX[..MBOX.find("From 2222").unwrap()];
+
+        assert_eq!(extract_diff(first), FIRST);
+    }
+
+    #[test]
+    fn plain_diffs_are_left_alone() {
+        assert_eq!(extract_diff(SECOND), SECOND);
+        assert_eq!(extract_diff("not a diff\n"), "not a diff\n");
+    }
+
+    #[test]
+    fn hunk_lengths_default_to_one() {
+        assert_eq!(hunk_lengths("@@ -1,3 +1,4 @@ fn main() {\n"), Some((3, 4)));
+        assert_eq!(hunk_lengths("@@ -1 +1,2 @@\n"), Some((1, 2)));
+        assert_eq!(hunk_lengths("@@ -0,0 +1 @@\n"), Some((0, 1)));
+        assert_eq!(hunk_lengths("-- \n"), None);
+    }
+
+    #[test]
+    fn mbox_is_split_into_one_patch_per_message() {
+        let path = std::env::temp_dir().join(format!("bipolar-{}-series.patch", std::process::id()));
+        fs::write(&path, MBOX).unwrap();
+
+        let patches = split_file(&path, "series.patch");
+        let _ = fs::remove_file(&path);
+        let patches = patches.unwrap();
+
+        let labels: Vec<&str> = patches.iter().map(|p| p.label.as_str()).collect();
+        assert_eq!(labels, ["series.patch#1", "series.patch#2"]);
+        assert_eq!(String::from_utf8_lossy(&patches[0].contents), FIRST);
+        assert_eq!(String::from_utf8_lossy(&patches[1].contents), SECOND);
+    }
+}
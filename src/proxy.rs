@@ -0,0 +1,625 @@
+use crate::{build, config, health, runner, utils};
+use std::{collections::{HashMap, HashSet}, io::{self, BufRead, BufReader, Write}, net::{Shutdown, SocketAddr, TcpListener, TcpStream}, sync::{Arc, RwLock}, thread, time::Duration};
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const IDENTITY_COOKIE: &str = "bipolar_id";
+const IDENTITY_MAX_AGE: u64 = 60 * 60 * 24 * 365;
+const HEALTH_REFRESH_MS: u64 = 1000;
+const WEIGHT_ROUNDS: usize = 100;
+
+struct Upstream {
+    shard: usize,
+    address: String,
+    weight: f64,
+}
+
+struct Router {
+    experiment: String,
+    identity: config::IdentitySource,
+    upstreams: Vec<Upstream>,
+    // shards the runner reports healthy, None routes to every shard
+    healthy: RwLock<Option<HashSet<usize>>>,
+}
+
+// the control holdout gets its percent, each slice of a pool (a treatment,
+// or the shards left without one) gets its split of the rest. slices are
+// rounded to whole shards and pools don't cross evenly, so weights start
+// out even and get scaled to one slice after the other until they settle
+fn shard_weights(
+    config: &config::ExperimentConfig,
+    hash_version: build::HashVersion,
+) -> Result<HashMap<usize, f64>, Box<dyn std::error::Error>> {
+    let assigned = build::assign_shards(config, hash_version)?;
+    let control = build::control_shards(config, hash_version)?;
+    let shards: Vec<usize> = (config.minmax.0..config.minmax.1)
+        .filter(|shard| !control.contains(shard))
+        .collect();
+
+    let mut slices: Vec<(Vec<usize>, f64)> = Vec::new();
+    for pool in build::treatment_pools(config)? {
+        let mut rest = shards.clone();
+        let mut left = 1.0;
+
+        for treatment in pool.treatments {
+            let name = build::treatment_name(treatment);
+            let (Some(split), Some(on)) = (config.assignment.split.get(name), assigned.get(name)) else {
+                continue;
+            };
+
+            let on: Vec<usize> = on.iter().copied().filter(|shard| shards.contains(shard)).collect();
+            rest.retain(|shard| !on.contains(shard));
+            left -= *split as f64 / 100.0;
+            slices.push((on, *split as f64 / 100.0));
+        }
+
+        slices.push((rest, left));
+    }
+
+    let mut weights: HashMap<usize, f64> = shards.iter().map(|&shard| (shard, 1.0)).collect();
+    for _ in 0..WEIGHT_ROUNDS {
+        for (slice, share) in &slices {
+            let sum: f64 = slice.iter().map(|shard| weights[shard]).sum();
+            if sum <= 0.0 {
+                continue;
+            }
+
+            for shard in slice {
+                *weights.get_mut(shard).unwrap() *= share / sum;
+            }
+        }
+    }
+
+    let held_out: Vec<usize> = (config.minmax.0..config.minmax.1)
+        .filter(|shard| control.contains(shard))
+        .collect();
+    let holdout = match held_out.is_empty() {
+        true => 0.0,
+        false => config.assignment.control.unwrap_or(0) as f64 / 100.0,
+    };
+
+    let sum: f64 = weights.values().sum();
+    if sum > 0.0 {
+        for weight in weights.values_mut() {
+            *weight *= (1.0 - holdout) / sum;
+        }
+    }
+    for shard in &held_out {
+        weights.insert(*shard, holdout / held_out.len() as f64);
+    }
+
+    let total: f64 = weights.values().sum();
+    if total <= 0.0 {
+        println!("⚠️ splits leave no traffic for any shard, routing evenly");
+        for weight in weights.values_mut() {
+            *weight = 1.0;
+        }
+    }
+
+    Ok(weights)
+}
+
+fn upstreams(
+    config: &config::ExperimentConfig,
+    lockfile: &build::LockFile,
+) -> Result<Vec<Upstream>, Box<dyn std::error::Error>> {
+    let weights = shard_weights(config, lockfile.hash_version)?;
+    let total: f64 = weights.values().sum();
+
+    let mut upstreams = Vec::new();
+    for shard in config.minmax.0..config.minmax.1 {
+        let address = config.shard_address(shard)
+            .ok_or(format!("no upstream or port configured for shard {}", shard))?;
+
+        upstreams.push(Upstream {
+            shard,
+            address,
+            weight: weights.get(&shard).copied().unwrap_or(0.0) / total,
+        });
+    }
+
+    if upstreams.is_empty() {
+        return Err("no shards to proxy to, check minmax".into());
+    }
+
+    Ok(upstreams)
+}
+
+// `point` is in 0..1 and gets spread over whatever weight `upstreams` add up to
+fn pick<'a>(upstreams: &[&'a Upstream], point: f64) -> &'a Upstream {
+    let point = point * upstreams.iter().map(|u| u.weight).sum::<f64>();
+
+    let mut acc = 0.0;
+    for upstream in upstreams {
+        acc += upstream.weight;
+        if point < acc {
+            return upstream;
+        }
+    }
+
+    upstreams.iter().rev().find(|u| u.weight > 0.0).unwrap_or(&upstreams[0])
+}
+
+// None when there's nothing to go by: no runner, or no health check
+fn healthy_shards() -> Option<HashSet<usize>> {
+    let state = runner::read_state().ok()?;
+    if state.health.is_empty() || !utils::pid_alive(state.pid, false) {
+        return None;
+    }
+
+    Some(state.health.iter()
+        .filter(|(_, health)| **health == health::Health::Healthy)
+        .filter_map(|(shard, _)| shard.parse().ok())
+        .collect())
+}
+
+// follows the runner's health checks, so traffic only goes to shards that are up
+fn watch_health(router: Arc<Router>) {
+    loop {
+        let healthy = healthy_shards();
+
+        if *router.healthy.read().unwrap() != healthy {
+            match &healthy {
+                Some(shards) => {
+                    let mut shards: Vec<_> = shards.iter().collect();
+                    shards.sort();
+                    println!("🩺 routing to healthy shards: {:?}", shards);
+                }
+                None => println!("🩺 no health checks to go by, routing to every shard"),
+            }
+            *router.healthy.write().unwrap() = healthy;
+        }
+
+        thread::sleep(Duration::from_millis(HEALTH_REFRESH_MS));
+    }
+}
+
+fn read_head(reader: &mut BufReader<TcpStream>) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+
+    loop {
+        let mut line = Vec::new();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            if lines.is_empty() {
+                return Ok(lines);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-head"));
+        }
+
+        size += read;
+        if size > MAX_HEAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "head too large"));
+        }
+
+        let line = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            if lines.is_empty() {
+                // tolerate stray newlines between messages
+                continue;
+            }
+            return Ok(lines);
+        }
+
+        lines.push(line);
+    }
+}
+
+fn headers<'a>(head: &'a [String], name: &'a str) -> impl Iterator<Item = &'a str> {
+    head.iter().skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+fn header<'a>(head: &'a [String], name: &'a str) -> Option<&'a str> {
+    headers(head, name).next()
+}
+
+fn cookie<'a>(head: &'a [String], name: &'a str) -> Option<&'a str> {
+    headers(head, "cookie")
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+        .filter(|value| !value.is_empty())
+}
+
+// returns the identity and whether it was just made up for this visitor
+fn identify(head: &[String], source: &config::IdentitySource, peer: &SocketAddr) -> (String, bool) {
+    let found = match source {
+        config::IdentitySource::Cookie { name } => cookie(head, name),
+        config::IdentitySource::Header { name } => header(head, name).filter(|v| !v.is_empty()),
+        config::IdentitySource::Ip => return (peer.ip().to_string(), false),
+    };
+
+    match found.or(cookie(head, IDENTITY_COOKIE)) {
+        Some(identity) => (identity.to_string(), false),
+        None => (format!("{:032x}", rand::random::<u128>()), true),
+    }
+}
+
+// where an identity lands in 0..1, the same every time
+fn point(parts: &[&[u8]]) -> f64 {
+    (utils::stable_hash(parts) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Router {
+    // only identities whose shard is down move, each to the same healthy
+    // shard every time, so nobody else gets reshuffled
+    fn route(&self, identity: &str) -> &Upstream {
+        let all: Vec<&Upstream> = self.upstreams.iter().collect();
+        let upstream = pick(&all, point(&[self.experiment.as_bytes(), identity.as_bytes()]));
+
+        let healthy = self.healthy.read().unwrap();
+        let Some(healthy) = healthy.as_ref().filter(|healthy| !healthy.contains(&upstream.shard)) else {
+            return upstream;
+        };
+
+        // with no healthy shard left, a 502 says more than routing nowhere
+        let candidates: Vec<&Upstream> = self.upstreams.iter().filter(|u| healthy.contains(&u.shard)).collect();
+        if candidates.is_empty() {
+            return upstream;
+        }
+
+        pick(&candidates, point(&[self.experiment.as_bytes(), identity.as_bytes(), b"unhealthy"]))
+    }
+}
+
+// one request per upstream connection, so the routing decision can never
+// leak into a keep-alive connection that belongs to someone else.
+// `replace` drops any existing header with the same name, `append` doesn't
+fn rewrite_head(head: &[String], replace: &[(&str, String)], append: &[(&str, String)]) -> String {
+    let upgrade = header(head, "upgrade").is_some();
+
+    let mut out = String::new();
+    out.push_str(&head[0]);
+    out.push_str("\r\n");
+
+    for line in head.iter().skip(1) {
+        let key = line.split_once(':').map(|(k, _)| k.trim()).unwrap_or("");
+        let hop_by_hop = ["connection", "keep-alive", "proxy-connection"]
+            .iter()
+            .any(|h| key.eq_ignore_ascii_case(h));
+        let replaced = replace.iter().any(|(k, _)| key.eq_ignore_ascii_case(k));
+
+        if (hop_by_hop && !upgrade) || replaced {
+            continue;
+        }
+
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+
+    if !upgrade {
+        out.push_str("Connection: close\r\n");
+    }
+
+    for (key, value) in replace.iter().chain(append) {
+        out.push_str(&format!("{}: {}\r\n", key, value));
+    }
+
+    out.push_str("\r\n");
+    out
+}
+
+fn bad_gateway(client: &mut TcpStream) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+}
+
+fn handle(client: TcpStream, router: &Router) -> Result<(), Box<dyn std::error::Error>> {
+    let peer = client.peer_addr()?;
+    let mut writer = client.try_clone()?;
+    let mut client_reader = BufReader::new(client);
+
+    let head = read_head(&mut client_reader)?;
+    if head.is_empty() {
+        return Ok(());
+    }
+
+    let (identity, fresh) = identify(&head, &router.identity, &peer);
+    let upstream = router.route(&identity);
+
+    let mut set_cookie = Vec::new();
+    if fresh {
+        set_cookie.push(("Set-Cookie", format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            IDENTITY_COOKIE, identity, IDENTITY_MAX_AGE,
+        )));
+    }
+
+    let server = match TcpStream::connect(&upstream.address) {
+        Ok(server) => server,
+        Err(e) => {
+            bad_gateway(&mut writer)?;
+            return Err(format!("shard {} at {} unreachable: {}", upstream.shard, upstream.address, e).into());
+        }
+    };
+
+    let forwarded_for = match header(&head, "x-forwarded-for") {
+        Some(existing) => format!("{}, {}", existing, peer.ip()),
+        None => peer.ip().to_string(),
+    };
+
+    let mut server_writer = server.try_clone()?;
+    server_writer.write_all(rewrite_head(&head, &[("X-Forwarded-For", forwarded_for)], &[]).as_bytes())?;
+
+    let buffered = client_reader.buffer().len();
+    server_writer.write_all(client_reader.buffer())?;
+    client_reader.consume(buffered);
+
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut server_writer);
+        let _ = server_writer.shutdown(Shutdown::Write);
+    });
+
+    let mut server_reader = BufReader::new(server);
+    loop {
+        let head = read_head(&mut server_reader)?;
+        if head.is_empty() {
+            bad_gateway(&mut writer)?;
+            return Err(format!("shard {} closed the connection without responding", upstream.shard).into());
+        }
+
+        let status = head[0].split_whitespace().nth(1).unwrap_or("");
+        if status == "101" {
+            writer.write_all(format!("{}\r\n\r\n", head.join("\r\n")).as_bytes())?;
+            break;
+        }
+
+        if status.starts_with('1') {
+            writer.write_all(rewrite_head(&head, &[], &[]).as_bytes())?;
+            continue;
+        }
+
+        writer.write_all(rewrite_head(&head, &[], &set_cookie).as_bytes())?;
+        break;
+    }
+
+    writer.write_all(server_reader.buffer())?;
+    let buffered = server_reader.buffer().len();
+    server_reader.consume(buffered);
+    io::copy(&mut server_reader, &mut writer)?;
+
+    let _ = writer.shutdown(Shutdown::Both);
+    let _ = server_reader.get_ref().shutdown(Shutdown::Both);
+    let _ = upload.join();
+
+    Ok(())
+}
+
+pub fn proxy(config: &config::ExperimentConfig, listen: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let strategy = match &config.assignment.strategy {
+        config::StrategyType::Proxy(strategy) => strategy,
+        _ => return Err("proxy requires the Proxy assignment strategy".into()),
+    };
+
+    let lockfile = build::read_lockfile()
+        .map_err(|e| format!("couldn't read lockfile, did you build? ({})", e))?;
+
+    let router = Arc::new(Router {
+        experiment: config.name.clone(),
+        identity: strategy.identity.clone().unwrap_or(config::IdentitySource::Cookie {
+            name: IDENTITY_COOKIE.to_string(),
+        }),
+        upstreams: upstreams(config, &lockfile)?,
+        healthy: RwLock::new(None),
+    });
+
+    let listen = listen
+        .or(strategy.listen.clone())
+        .unwrap_or(DEFAULT_LISTEN.to_string());
+    let listener = TcpListener::bind(&listen)?;
+
+    println!("🔀 proxying {} across {} shards", listen, router.upstreams.len());
+    for upstream in router.upstreams.iter() {
+        println!("  shard {} -> {} ({:.1}%)", upstream.shard, upstream.address, upstream.weight * 100.0);
+    }
+
+    let watched = router.clone();
+    thread::spawn(move || watch_health(watched));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("⚠️ couldn't accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let router = router.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &router) {
+                eprintln!("⚠️ proxy error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::tests::{experiment, PROXY};
+
+    fn router(weights: &[f64]) -> Router {
+        Router {
+            experiment: "test".to_string(),
+            identity: config::IdentitySource::Ip,
+            upstreams: weights.iter().enumerate()
+                .map(|(shard, weight)| Upstream { shard, address: format!("127.0.0.1:{}", 3000 + shard), weight: *weight })
+                .collect(),
+            healthy: RwLock::new(None),
+        }
+    }
+
+    // share of the traffic that goes to shards with `treatment` on them
+    fn treatment_share(config: &config::ExperimentConfig, treatment: &str) -> f64 {
+        let assigned = build::assign_shards(config, build::CURRENT_HASH_VERSION).unwrap();
+        let weights = shard_weights(config, build::CURRENT_HASH_VERSION).unwrap();
+        let total: f64 = weights.values().sum();
+
+        assigned[treatment].iter().map(|shard| weights[shard]).sum::<f64>() / total
+    }
+
+    // share of `identities` routed to each shard
+    fn observed(router: &Router, identities: usize) -> Vec<f64> {
+        let mut counts = vec![0; router.upstreams.len()];
+        for i in 0..identities {
+            counts[router.route(&format!("user-{}", i)).shard] += 1;
+        }
+
+        counts.iter().map(|count| *count as f64 / identities as f64).collect()
+    }
+
+    #[test]
+    fn same_identity_always_gets_the_same_shard() {
+        let router = router(&[0.5, 0.3, 0.2]);
+
+        for i in 0..1000 {
+            let identity = format!("user-{}", i);
+            let shard = router.route(&identity).shard;
+            assert!((0..10).all(|_| router.route(&identity).shard == shard));
+        }
+    }
+
+    #[test]
+    fn observed_split_matches_weights() {
+        let weights = [0.5, 0.3, 0.2];
+        let observed = observed(&router(&weights), 100_000);
+
+        for (weight, share) in weights.iter().zip(&observed) {
+            assert!((weight - share).abs() < 0.01, "expected {:?}, got {:?}", weights, observed);
+        }
+    }
+
+    #[test]
+    fn pick_spreads_the_point_over_the_weights() {
+        let router = router(&[0.5, 0.0, 0.5]);
+        let upstreams: Vec<&Upstream> = router.upstreams.iter().collect();
+
+        assert_eq!(pick(&upstreams, 0.0).shard, 0);
+        assert_eq!(pick(&upstreams, 0.49).shard, 0);
+        assert_eq!(pick(&upstreams, 0.5).shard, 2);
+        assert_eq!(pick(&upstreams, 0.99).shard, 2);
+    }
+
+    #[test]
+    fn weights_follow_the_splits_within_a_layer() {
+        let config = experiment(100, PROXY, r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "checkout"
+        "#, "a = 30\nb = 70");
+
+        assert!((treatment_share(&config, "a") - 0.3).abs() < 1e-6);
+        assert!((treatment_share(&config, "b") - 0.7).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weights_follow_the_splits_across_layers() {
+        let config = experiment(100, PROXY, r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "c"
+            ref_ = "c"
+        "#, "a = 30\nb = 20\nc = 40");
+
+        assert!((treatment_share(&config, "a") - 0.3).abs() < 1e-6);
+        assert!((treatment_share(&config, "b") - 0.2).abs() < 1e-6);
+        assert!((treatment_share(&config, "c") - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weights_make_up_for_rounded_slices() {
+        // a gets 3 of the 10 shards, b gets 2
+        let config = experiment(10, PROXY, r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "b"
+            ref_ = "b"
+            layer = "checkout"
+            [[treatments]]
+            type = "Branch"
+            name = "c"
+            ref_ = "c"
+        "#, "a = 25\nb = 25\nc = 50");
+
+        assert!((treatment_share(&config, "a") - 0.25).abs() < 1e-6);
+        assert!((treatment_share(&config, "b") - 0.25).abs() < 1e-6);
+        assert!((treatment_share(&config, "c") - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn control_holdout_gets_its_share_of_identities() {
+        let mut config = experiment(100, PROXY, r#"
+            [[treatments]]
+            type = "Branch"
+            name = "a"
+            ref_ = "a"
+        "#, "a = 50");
+        config.assignment.control = Some(10);
+
+        let weights = shard_weights(&config, build::CURRENT_HASH_VERSION).unwrap();
+        let total: f64 = weights.values().sum();
+        let mut router = router(&[]);
+        router.upstreams = (0..100)
+            .map(|shard| Upstream { shard, address: String::new(), weight: weights[&shard] / total })
+            .collect();
+
+        let observed = observed(&router, 100_000);
+        let share = |shards: &[usize]| shards.iter().map(|shard| observed[*shard]).sum::<f64>();
+
+        let control = build::control_shards(&config, build::CURRENT_HASH_VERSION).unwrap();
+        let assigned = build::assign_shards(&config, build::CURRENT_HASH_VERSION).unwrap();
+        assert!((share(&control) - 0.1).abs() < 0.01, "control got {}", share(&control));
+        // a's split is of whatever the holdout leaves
+        assert!((share(&assigned["a"]) - 0.45).abs() < 0.01, "a got {}", share(&assigned["a"]));
+    }
+
+    #[test]
+    fn only_identities_on_unhealthy_shards_move() {
+        let router = router(&[0.25, 0.25, 0.25, 0.25]);
+        let before: Vec<usize> = (0..10_000).map(|i| router.route(&format!("user-{}", i)).shard).collect();
+
+        *router.healthy.write().unwrap() = Some(HashSet::from([0, 1, 3]));
+        let after: Vec<usize> = (0..10_000).map(|i| router.route(&format!("user-{}", i)).shard).collect();
+
+        for (before, after) in before.iter().zip(&after) {
+            match before {
+                2 => assert_ne!(*after, 2),
+                _ => assert_eq!(after, before),
+            }
+        }
+
+        // and they come back once it's healthy again
+        *router.healthy.write().unwrap() = Some(HashSet::from([0, 1, 2, 3]));
+        let back: Vec<usize> = (0..10_000).map(|i| router.route(&format!("user-{}", i)).shard).collect();
+        assert_eq!(back, before);
+    }
+}
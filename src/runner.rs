@@ -1,50 +1,804 @@
-use crate::{config, build, utils};
-use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::Duration};
+use crate::{config, build, control, health, logs, utils};
+use serde::{Serialize, Deserialize};
+use std::{collections::HashMap, fs::{self, OpenOptions}, path::PathBuf, process::{self, Child, Command, Stdio}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant}};
 
-pub fn run(config: &config::ExperimentConfig) -> Result<(), Box<dyn std::error::Error>> {
-    if config.hooks.run.is_none() {
-        return Err("no run hook found".into());
+const DEFAULT_BACKOFF_MS: u64 = 1000;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 60_000;
+const DEFAULT_MAX_RESTARTS: usize = 5;
+const DEFAULT_CRASH_WINDOW_SECS: u64 = 60;
+const DEFAULT_GRACE_PERIOD_MS: u64 = 10_000;
+// how long a rolling restart waits for a batch without a health check to go by
+const DEFAULT_ROLLING_TIMEOUT_SECS: u64 = 60;
+// how long a shard without a health check has to stay up before a rolling
+// restart takes the next batch down
+const ROLLING_SETTLE_SECS: u64 = 5;
+
+pub const STATE_FILE: &str = "runner.toml";
+
+// written by a running `bipolar run` so other commands can find its processes
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RunnerState {
+    pub pid: u32,
+    // shard index -> pid of its run hook, which leads the shard's process group
+    pub shards: HashMap<String, u32>,
+    // every shard passed its health check, or the startup timeout ran out
+    #[serde(default)]
+    pub ready: bool,
+    // shard index -> health, empty without a health check
+    #[serde(default)]
+    pub health: HashMap<String, health::Health>,
+}
+
+pub fn get_state_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(build::get_build_dir()?.join(STATE_FILE))
+}
+
+pub fn read_state() -> Result<RunnerState, Box<dyn std::error::Error>> {
+    Ok(toml::from_str(&fs::read_to_string(get_state_path()?)?)?)
+}
+
+fn write_state(state: &RunnerState) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(get_state_path()?, toml::to_string(state)?)?;
+
+    Ok(())
+}
+
+// the state of a runner that's still alive. a state file left behind by a
+// runner that got killed is cleaned up, along with any shards it left
+// running. pids get reused, so only groups still running in their shard's
+// directory are killed
+pub fn running_state() -> Result<Option<RunnerState>, Box<dyn std::error::Error>> {
+    if !get_state_path()?.exists() {
+        return Ok(None);
+    }
+
+    let state = read_state()?;
+    if utils::pid_alive(state.pid, false) {
+        return Ok(Some(state));
+    }
+
+    println!("🧹 runner pid {} is gone, cleaning up after it", state.pid);
+    for (shard, pid) in &state.shards {
+        if !utils::pid_alive(*pid, true) {
+            continue;
+        }
+
+        let dir = shard.parse().ok().and_then(|shard| build::get_shard_dir(shard).ok());
+        match dir {
+            Some(dir) if utils::group_runs_in(*pid, &dir) => {
+                println!("🔪 shard {} outlived its runner, killing it", shard);
+                utils::signal_pid(*pid, true, true);
+            }
+            _ => println!("⚠️ pid {} of shard {} doesn't run in the shard anymore, leaving it alone", pid, shard),
+        }
+    }
+
+    fs::remove_file(get_state_path()?)?;
+    Ok(None)
+}
+
+struct Limits {
+    backoff: Duration,
+    max_backoff: Duration,
+    max_restarts: usize,
+    crash_window: Duration,
+    grace_period: Duration,
+    health: Option<health::Settings>,
+}
+
+impl Limits {
+    fn from_config(config: &config::ExperimentConfig) -> Limits {
+        let supervisor = config.supervisor.as_ref();
+
+        Limits {
+            backoff: Duration::from_millis(supervisor.and_then(|s| s.backoff_ms).unwrap_or(DEFAULT_BACKOFF_MS)),
+            max_backoff: Duration::from_millis(supervisor.and_then(|s| s.max_backoff_ms).unwrap_or(DEFAULT_MAX_BACKOFF_MS)),
+            max_restarts: supervisor.and_then(|s| s.max_restarts).unwrap_or(DEFAULT_MAX_RESTARTS),
+            crash_window: Duration::from_secs(supervisor.and_then(|s| s.crash_window_secs).unwrap_or(DEFAULT_CRASH_WINDOW_SECS)),
+            grace_period: Duration::from_millis(supervisor.and_then(|s| s.grace_period_ms).unwrap_or(DEFAULT_GRACE_PERIOD_MS)),
+            health: health::Settings::from_config(config),
+        }
+    }
+}
+
+struct Shard {
+    id: usize,
+    child: Option<Child>,
+    started_at: Instant,
+    restart_at: Option<Instant>,
+    backoff: Duration,
+    crashes: Vec<Instant>,
+    restarts: usize,
+    given_up: bool,
+    // stopped on request, not supervised until restarted
+    stopped: bool,
+    stopping: Option<Stopping>,
+    health: health::Checker,
+    // when the build it was last started from finished
+    built_at: Option<u64>,
+}
+
+// a stop on request that's under way. supervise finishes it, so the runner
+// never sits out a shard's grace period
+struct Stopping {
+    deadline: Instant,
+    // start it again once it's down instead of leaving it stopped
+    restart: bool,
+}
+
+fn built_at(lockfile: &build::LockFile, shard: usize) -> Option<u64> {
+    lockfile.builds.get(&shard.to_string()).map(|build| build.finished_at)
+}
+
+fn spawn_shard(
+    config: &config::ExperimentConfig,
+    lockfile: &build::LockFile,
+    shard: usize,
+) -> Result<Child, Box<dyn std::error::Error>> {
+    let hook = config.hooks.run.as_ref().ok_or("no run hook found")?;
+    let shard_dir = build::get_shard_dir(shard)?;
+
+    utils::run_command_string(
+        hook,
+        shard_dir.to_str().unwrap(),
+        &build::shard_env(config, lockfile, shard),
+        Some(&logs::shard_log_target(lockfile, shard, "run")?),
+        true,
+    )
+}
+
+impl Shard {
+    fn started(&mut self, child: Child, lockfile: &build::LockFile) {
+        self.child = Some(child);
+        self.started_at = Instant::now();
+        self.health.reset();
+        self.built_at = built_at(lockfile, self.id);
+    }
+
+    fn crashed(&mut self, limits: &Limits, reason: String) {
+        let now = Instant::now();
+
+        // a shard that stayed up for a whole window starts over
+        if now.duration_since(self.started_at) >= limits.crash_window {
+            self.backoff = limits.backoff;
+        }
+
+        self.crashes.retain(|at| now.duration_since(*at) < limits.crash_window);
+        self.crashes.push(now);
+
+        if self.crashes.len() > limits.max_restarts {
+            println!(
+                "💀 shard {} {}, crashed {} times in {:?}, giving up",
+                self.id, reason, self.crashes.len(), limits.crash_window,
+            );
+            self.given_up = true;
+            return;
+        }
+
+        println!("💥 shard {} {}, restarting in {:?}", self.id, reason, self.backoff);
+        self.restart_at = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(limits.max_backoff);
+    }
+
+    fn supervise(&mut self, config: &config::ExperimentConfig, lockfile: &build::LockFile, limits: &Limits) {
+        if self.stopping.is_some() {
+            self.finish_stop(config, lockfile, limits);
+            return;
+        }
+
+        if self.given_up || self.stopped {
+            return;
+        }
+
+        if let Some(child) = &mut self.child {
+            let reason = match child.try_wait() {
+                Ok(None) => {
+                    self.check_health(config, lockfile, limits);
+                    return;
+                }
+                Ok(Some(status)) => format!("exited with {}", status),
+                Err(e) => format!("couldn't be polled ({})", e),
+            };
+
+            // don't leave anything the wrapper spawned bound to the port
+            utils::signal_group(child, true);
+
+            self.child = None;
+            self.health.reset();
+            self.crashed(limits, reason);
+            return;
+        }
+
+        if self.restart_at.is_some_and(|at| Instant::now() >= at) {
+            self.restart_at = None;
+            self.restarts += 1;
+
+            match spawn_shard(config, lockfile, self.id) {
+                Ok(child) => {
+                    println!("🔁 restarted shard {} (restart #{})", self.id, self.restarts);
+                    self.started(child, lockfile);
+                }
+                Err(e) => self.crashed(limits, format!("failed to start ({})", e)),
+            }
+        }
     }
+}
 
+impl Shard {
+    fn check_health(&mut self, config: &config::ExperimentConfig, lockfile: &build::LockFile, limits: &Limits) {
+        let Some(settings) = &limits.health else {
+            return;
+        };
+
+        match self.health.poll(settings, config, lockfile, self.id) {
+            Some(health::Health::Healthy) => println!("💚 shard {} is healthy", self.id),
+            Some(health::Health::Unhealthy) => println!(
+                "🩺 shard {} is unhealthy: {}",
+                self.id, self.health.last_error.as_deref().unwrap_or("unknown"),
+            ),
+            _ => {}
+        }
+    }
+
+    // stops the shard like shutdown does, without touching the others.
+    // only signals it, supervise waits out the grace period
+    fn begin_stop(&mut self, limits: &Limits, restart: bool) {
+        if let Some(child) = &mut self.child {
+            if self.stopping.is_none() {
+                utils::signal_group(child, false);
+            }
+        }
+
+        let deadline = Instant::now() + limits.grace_period;
+        self.stopping = Some(Stopping {
+            deadline: self.stopping.as_ref().map_or(deadline, |stopping| stopping.deadline),
+            restart,
+        });
+        self.restart_at = None;
+        // it's on its way down, nothing should be routed to it
+        self.health.reset();
+    }
+
+    fn finish_stop(&mut self, config: &config::ExperimentConfig, lockfile: &build::LockFile, limits: &Limits) {
+        let Some(stopping) = &self.stopping else {
+            return;
+        };
+
+        if let Some(child) = &mut self.child {
+            if utils::group_alive(child) {
+                if Instant::now() < stopping.deadline {
+                    return;
+                }
+
+                println!("🔪 shard {} didn't stop in time, killing it", self.id);
+                utils::signal_group(child, true);
+            }
+            let _ = child.wait();
+        }
+
+        self.child = None;
+        self.health.reset();
+
+        if !self.stopping.take().is_some_and(|stopping| stopping.restart) {
+            self.stopped = true;
+            println!("🛑 stopped shard {} on request", self.id);
+            return;
+        }
+
+        // asked for by the user, so it doesn't count as a crash
+        self.backoff = limits.backoff;
+        self.crashes.clear();
+        self.given_up = false;
+        self.stopped = false;
+        self.restarts += 1;
+
+        match spawn_shard(config, lockfile, self.id) {
+            Ok(child) => {
+                println!("🔁 restarted shard {} on request", self.id);
+                self.started(child, lockfile);
+            }
+            Err(e) => self.crashed(limits, format!("failed to start ({})", e)),
+        }
+    }
+
+    fn info(&self, checked: bool) -> control::ShardInfo {
+        let state = if self.stopping.is_some() {
+            "stopping"
+        } else if self.child.is_some() {
+            "running"
+        } else if self.stopped {
+            "stopped"
+        } else if self.given_up {
+            "given up"
+        } else {
+            "backing off"
+        };
+
+        control::ShardInfo {
+            shard: self.id,
+            state: state.to_string(),
+            pid: self.child.as_ref().map(|child| child.id()),
+            restarts: self.restarts,
+            uptime_secs: self.child.as_ref().map(|_| self.started_at.elapsed().as_secs()),
+            health: self.child.as_ref().filter(|_| checked).map(|_| self.health.health),
+            built_at: self.built_at,
+        }
+    }
+}
+
+// everything a running experiment can be asked through the control socket
+struct Runner {
+    config: config::ExperimentConfig,
+    lockfile: build::LockFile,
+    limits: Limits,
+    shards: Vec<Shard>,
+    started_at: Instant,
+    ready: bool,
+}
+
+impl Runner {
+    fn state(&self) -> RunnerState {
+        let running = || self.shards.iter().filter(|shard| shard.child.is_some());
+
+        RunnerState {
+            pid: process::id(),
+            shards: running()
+                .map(|shard| (shard.id.to_string(), shard.child.as_ref().unwrap().id()))
+                .collect(),
+            ready: self.ready,
+            health: match self.limits.health {
+                Some(_) => running().map(|shard| (shard.id.to_string(), shard.health.health)).collect(),
+                None => HashMap::new(),
+            },
+        }
+    }
+
+    // the experiment counts as started once every shard that's meant to run
+    // is healthy, or when the startup timeout runs out on the ones that aren't
+    fn check_ready(&mut self) {
+        let Some(settings) = &self.limits.health else {
+            self.ready = true;
+            return;
+        };
+
+        let waiting: Vec<&Shard> = self.shards.iter()
+            .filter(|shard| !shard.stopped && !shard.given_up && shard.health.health != health::Health::Healthy)
+            .collect();
+
+        if waiting.is_empty() {
+            println!("✅ every shard is healthy, experiment started");
+            self.ready = true;
+        } else if self.started_at.elapsed() >= settings.startup_timeout {
+            for shard in waiting {
+                println!(
+                    "⚠️ shard {} still isn't healthy after {:?}: {}",
+                    shard.id, settings.startup_timeout, shard.health.last_error.as_deref().unwrap_or("no check finished"),
+                );
+            }
+            println!("🚦 experiment started without them");
+            self.ready = true;
+        }
+    }
+
+    fn handle(&mut self, request: control::Request) -> control::Response {
+        match request {
+            control::Request::List => control::Response {
+                shards: Some(self.shards.iter().map(|s| s.info(self.limits.health.is_some())).collect()),
+                ..control::Response::ok()
+            },
+
+            control::Request::Restart { shard } => {
+                // pick up whatever was built since
+                match build::read_lockfile() {
+                    Ok(lockfile) => self.lockfile = lockfile,
+                    Err(e) => eprintln!("⚠️ couldn't re-read lockfile, restarting with the old one: {}", e),
+                }
+
+                let mut restarted = 0;
+                for s in self.shards.iter_mut().filter(|s| shard.is_none_or(|shard| s.id == shard)) {
+                    s.begin_stop(&self.limits, true);
+                    restarted += 1;
+                }
+
+                match restarted {
+                    0 => control::Response::error(format!("shard {} isn't run by this runner", shard.unwrap_or_default())),
+                    _ => control::Response::ok(),
+                }
+            }
+
+            control::Request::Stop { shard } => {
+                let Some(s) = self.shards.iter_mut().find(|s| s.id == shard) else {
+                    return control::Response::error(format!("shard {} isn't run by this runner", shard));
+                };
+
+                s.begin_stop(&self.limits, false);
+
+                control::Response::ok()
+            }
+
+            control::Request::Reload => {
+                let config = match config::load_config() {
+                    Ok(config) => config,
+                    Err(e) => return control::Response::error(format!("couldn't load config: {}", e)),
+                };
+
+                if config.minmax != self.config.minmax {
+                    return control::Response::error("minmax changed, restart the runner to pick it up");
+                }
+
+                let lockfile = match build::read_lockfile() {
+                    Ok(lockfile) => lockfile,
+                    Err(e) => return control::Response::error(format!("couldn't read lockfile: {}", e)),
+                };
+
+                set_environment(&config);
+                self.limits = Limits::from_config(&config);
+                self.config = config;
+                self.lockfile = lockfile;
+                println!("🔄 reloaded config and lockfile, restart shards to apply them");
+
+                control::Response::ok()
+            }
+
+            control::Request::Stats => control::Response {
+                stats: Some(control::Stats {
+                    pid: process::id(),
+                    uptime_secs: self.started_at.elapsed().as_secs(),
+                    shards: self.shards.len(),
+                    running: self.shards.iter().filter(|s| s.child.is_some()).count(),
+                    restarts: self.shards.iter().map(|s| s.restarts).sum(),
+                    given_up: self.shards.iter().filter(|s| s.given_up).count(),
+                }),
+                ..control::Response::ok()
+            },
+        }
+    }
+}
+
+fn set_environment(config: &config::ExperimentConfig) {
     if let Some(environment) = &config.environment {
         for (key, value) in environment {
             std::env::set_var(key, value);
         }
     }
+}
 
-    let mut children = Vec::new();
+fn shutdown(shards: &mut [Shard], limits: &Limits) {
+    println!("stopping shards, grace period {:?}", limits.grace_period);
 
-    for shard in config.minmax.0..config.minmax.1 {
-        let shard_dir = build::get_shard_dir(shard)?;
+    let mut children: Vec<(usize, &mut Child)> = shards.iter_mut()
+        .filter_map(|shard| shard.child.as_mut().map(|child| (shard.id, child)))
+        .collect();
 
-        let hook = config.hooks.run.clone();
-        if let Some(hook) = hook {
-            println!("running for shard {}", shard);
-            let child = utils::run_command_string(&hook, &shard_dir.to_str().unwrap(), true)?;
-            children.push(child);
+    for (_, child) in children.iter_mut() {
+        utils::signal_group(child, false);
+    }
 
-            thread::sleep(Duration::from_millis(500));
+    let deadline = Instant::now() + limits.grace_period;
+    while Instant::now() < deadline {
+        children.retain_mut(|(_, child)| utils::group_alive(child));
+        if children.is_empty() {
+            break;
         }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    for (id, child) in children.iter_mut() {
+        println!("🔪 shard {} didn't stop in time, killing it", id);
+        utils::signal_group(child, true);
+        let _ = child.wait();
+    }
+}
+
+// starts `bipolar run` in its own session with output going to the runner
+// log, and returns once it's up
+fn detach() -> Result<(), Box<dyn std::error::Error>> {
+    let log_path = logs::runner_log_path()?;
+    if let Some(parent) = log_path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("run")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    // out of the terminal's session, so closing it doesn't take the runner along
+    #[cfg(unix)]
+    unsafe {
+        use std::os::unix::process::CommandExt;
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(format!("runner exited with {}, see {}", status, log_path.display()).into());
+        }
 
+        // up once the shards are healthy, not just spawned
+        if read_state().is_ok_and(|state| state.pid == child.id() && state.ready) {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    println!("🚀 runner started in the background (pid {}), logs at {}", child.id(), log_path.display());
+    Ok(())
+}
+
+pub fn run(config: config::ExperimentConfig, detached: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if config.hooks.run.is_none() {
+        return Err("no run hook found".into());
+    }
+
+    if let Some(state) = running_state()? {
+        return Err(format!("already running (pid {}), `bipolar stop` it first", state.pid).into());
+    }
+
+    if detached {
+        return detach();
+    }
+
+    set_environment(&config);
+
+    let lockfile = build::read_lockfile()
+        .map_err(|e| format!("couldn't read lockfile, did you build? ({})", e))?;
+    let limits = Limits::from_config(&config);
+
+    // installed before spawning so an early SIGTERM still stops shards cleanly
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
 
-    println!("waiting for ctrl-c...");
-
     ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
     }).unwrap();
 
+    let mut runner = Runner {
+        config,
+        lockfile,
+        limits,
+        shards: Vec::new(),
+        started_at: Instant::now(),
+        ready: false,
+    };
+
+    let mut state = runner.state();
+    write_state(&state)?;
+
+    #[cfg(unix)]
+    let requests = control::listen()?;
+    #[cfg(windows)]
+    let requests = std::sync::mpsc::channel::<control::Pending>().1;
+
+    for shard in runner.config.minmax.0..runner.config.minmax.1 {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match runner.config.shard_port(shard) {
+            Some(port) => println!("running for shard {} on port {}", shard, port),
+            None => println!("running for shard {}", shard),
+        }
+
+        let child = match spawn_shard(&runner.config, &runner.lockfile, shard) {
+            Ok(child) => child,
+            Err(e) => {
+                shutdown(&mut runner.shards, &runner.limits);
+                let _ = fs::remove_file(get_state_path()?);
+                let _ = fs::remove_file(control::get_socket_path()?);
+                return Err(format!("couldn't start shard {}: {}", shard, e).into());
+            }
+        };
+
+        runner.shards.push(Shard {
+            id: shard,
+            child: Some(child),
+            started_at: Instant::now(),
+            restart_at: None,
+            backoff: runner.limits.backoff,
+            crashes: Vec::new(),
+            restarts: 0,
+            given_up: false,
+            stopped: false,
+            stopping: None,
+            health: health::Checker::new(),
+            built_at: built_at(&runner.lockfile, shard),
+        });
+
+        state = runner.state();
+        write_state(&state)?;
+
+        // without a health check there's no telling when it's up, give it a moment
+        if runner.limits.health.is_none() {
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+
+    println!("waiting for ctrl-c or SIGTERM...");
+
     while running.load(Ordering::SeqCst) {
+        for shard in &mut runner.shards {
+            shard.supervise(&runner.config, &runner.lockfile, &runner.limits);
+        }
+
+        for (request, reply) in requests.try_iter() {
+            let _ = reply.send(runner.handle(request));
+        }
+
+        if !runner.ready {
+            runner.check_ready();
+        }
+
+        let current = runner.state();
+        if current != state {
+            if let Err(e) = write_state(&current) {
+                eprintln!("⚠️ couldn't write runner state: {}", e);
+            }
+            state = current;
+        }
+
         thread::sleep(Duration::from_millis(100));
     }
 
-    println!("killing children");
+    shutdown(&mut runner.shards, &runner.limits);
+    let _ = fs::remove_file(get_state_path()?);
+    let _ = fs::remove_file(control::get_socket_path()?);
+
+    for shard in &runner.shards {
+        let state = if shard.given_up { " (gave up)" } else { "" };
+        println!("shard {}: {} restarts{}", shard.id, shard.restarts, state);
+    }
+
+    Ok(())
+}
+
+pub fn stop(config: &config::ExperimentConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(state) = running_state()? else {
+        println!("runner isn't running");
+        return Ok(());
+    };
+
+    println!("stopping runner (pid {})", state.pid);
+    utils::signal_pid(state.pid, false, false);
+
+    // the runner gives its shards the grace period, give it a bit on top
+    let deadline = Instant::now() + Limits::from_config(config).grace_period + Duration::from_secs(5);
+    while utils::pid_alive(state.pid, false) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    if utils::pid_alive(state.pid, false) {
+        println!("🔪 runner didn't stop in time, killing it and its shards");
+        utils::signal_pid(state.pid, false, true);
+        for pid in state.shards.values() {
+            utils::signal_pid(*pid, true, true);
+        }
+        let _ = fs::remove_file(get_state_path()?);
+    }
+
+    println!("🛑 runner stopped");
+    Ok(())
+}
+
+// restarts one shard, or all of them, inside the running runner. the
+// runner checks the shard, so a bad one is an error here
+pub fn restart(shard: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    let state = running_state()?.ok_or("runner isn't running, start it with `bipolar run`")?;
+
+    control::send(&control::Request::Restart { shard })?;
+
+    match shard {
+        Some(shard) => println!("🔁 runner (pid {}) is restarting shard {}", state.pid, shard),
+        None => println!("🔁 runner (pid {}) is restarting all shards", state.pid),
+    }
+
+    Ok(())
+}
+
+pub fn stop_shard(shard: usize) -> Result<(), Box<dyn std::error::Error>> {
+    running_state()?.ok_or("runner isn't running")?;
+    control::send(&control::Request::Stop { shard })?;
+
+    println!("🛑 stopping shard {}, `bipolar restart --shard {}` brings it back", shard, shard);
+    Ok(())
+}
+
+// shards that were rebuilt since they were started, stopped ones stay stopped
+fn outdated_shards(infos: &[control::ShardInfo], lockfile: &build::LockFile) -> Vec<usize> {
+    let mut outdated = Vec::new();
+    for info in infos.iter().filter(|info| info.state != "stopped") {
+        match lockfile.builds.get(&info.shard.to_string()) {
+            Some(build::ShardBuild { error: Some(_), .. }) => {
+                println!("⚠️ shard {}'s last build failed, leaving it as it is", info.shard);
+            }
+            Some(build::ShardBuild { finished_at, error: None }) if info.built_at != Some(*finished_at) => {
+                outdated.push(info.shard);
+            }
+            _ => {}
+        }
+    }
+
+    outdated
+}
+
+// up means running, and healthy when there's a health check. without one
+// all there is to go by is the shard staying up for a while
+fn wait_until_up(shards: &[usize], timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let infos = control::send(&control::Request::List)?.shards.unwrap_or_default();
+        let mut down = Vec::new();
+
+        for shard in shards {
+            let info = infos.iter().find(|info| info.shard == *shard);
+            if info.is_some_and(|info| info.state == "given up") {
+                return Err(format!("shard {} keeps crashing, stopping the rollout here", shard).into());
+            }
+
+            let up = info.is_some_and(|info| info.state == "running" && match info.health {
+                Some(health) => health == health::Health::Healthy,
+                None => info.uptime_secs.is_some_and(|uptime| uptime >= ROLLING_SETTLE_SECS),
+            });
+            if !up {
+                down.push(*shard);
+            }
+        }
+
+        if down.is_empty() {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "shards {:?} aren't back up after {:?}, stopping the rollout so the rest keep serving",
+                down, timeout,
+            ).into());
+        }
+
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// restarts the shards that were rebuilt since they started, `batch` at a
+// time. every batch has to be back up before the next one goes down, so the
+// experiment keeps serving while a new build rolls out
+pub fn rolling_restart(config: &config::ExperimentConfig, batch: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let state = running_state()?.ok_or("runner isn't running, start it with `bipolar run`")?;
+    let lockfile = build::read_lockfile()
+        .map_err(|e| format!("couldn't read lockfile, did you build? ({})", e))?;
+    let timeout = health::Settings::from_config(config)
+        .map(|settings| settings.startup_timeout)
+        .unwrap_or(Duration::from_secs(DEFAULT_ROLLING_TIMEOUT_SECS));
+
+    let infos = control::send(&control::Request::List)?.shards.unwrap_or_default();
+    let outdated = outdated_shards(&infos, &lockfile);
+    if outdated.is_empty() {
+        println!("✨ every shard already runs its latest build");
+        return Ok(());
+    }
+
+    let batch = batch.max(1);
+    println!("🔁 rolling restart of shards {:?}, {} at a time (runner pid {})", outdated, batch, state.pid);
+
+    for shards in outdated.chunks(batch) {
+        for shard in shards {
+            control::send(&control::Request::Restart { shard: Some(*shard) })?;
+        }
 
-    for mut child in children {
-        child.kill().unwrap();
+        wait_until_up(shards, timeout)?;
+        println!("✅ shards {:?} are back up", shards);
     }
 
+    println!("🚀 shards {:?} run their latest build", outdated);
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,159 @@
+use crate::{build, config, health, runner, utils};
+use git2::{Repository, StatusOptions};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize)]
+struct RunnerStatus {
+    pid: u32,
+    alive: bool,
+    // every shard came up healthy, or the startup timeout ran out
+    ready: bool,
+}
+
+#[derive(Serialize)]
+struct ShardStatus {
+    shard: usize,
+    treatments: Vec<String>,
+    held_out: bool,
+    // None when the shard checkout is missing
+    head: Option<String>,
+    dirty: Option<bool>,
+    build: Option<build::ShardBuild>,
+    pid: Option<u32>,
+    running: bool,
+    // None without a health check
+    health: Option<health::Health>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    experiment: String,
+    runner: Option<RunnerStatus>,
+    shards: Vec<ShardStatus>,
+}
+
+// tracked files that differ from HEAD, build output nobody committed doesn't count
+fn is_dirty(repo: &Repository) -> Result<bool, git2::Error> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+
+    Ok(!repo.statuses(Some(&mut options))?.is_empty())
+}
+
+fn ago(unix: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let secs = now.saturating_sub(unix);
+
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn health_label(health: health::Health) -> &'static str {
+    match health {
+        health::Health::Starting => "starting",
+        health::Health::Healthy => "healthy",
+        health::Health::Unhealthy => "unhealthy",
+    }
+}
+
+fn collect(config: &config::ExperimentConfig) -> Result<Status, Box<dyn std::error::Error>> {
+    let lockfile = build::read_lockfile()
+        .map_err(|e| format!("couldn't read lockfile, did you build? ({})", e))?;
+
+    let state = runner::read_state().ok();
+    let alive = state.as_ref().is_some_and(|state| utils::pid_alive(state.pid, false));
+
+    let mut shards = Vec::new();
+    for shard in config.minmax.0..config.minmax.1 {
+        let repo = Repository::open(build::get_shard_dir(shard)?).ok();
+        let head = repo.as_ref()
+            .and_then(|repo| repo.head().ok())
+            .and_then(|head| head.target())
+            .map(|oid| oid.to_string());
+        let dirty = match &repo {
+            Some(repo) => Some(is_dirty(repo)?),
+            None => None,
+        };
+
+        // a state file left behind by a runner that was killed doesn't count
+        let pid = state.as_ref()
+            .filter(|_| alive)
+            .and_then(|state| state.shards.get(&shard.to_string()).copied());
+
+        let health = state.as_ref()
+            .filter(|_| alive)
+            .and_then(|state| state.health.get(&shard.to_string()).copied());
+
+        shards.push(ShardStatus {
+            shard,
+            treatments: build::shard_treatments(&lockfile, shard),
+            held_out: lockfile.control.contains(&shard),
+            head,
+            dirty,
+            build: lockfile.builds.get(&shard.to_string()).cloned(),
+            pid,
+            running: pid.is_some_and(|pid| utils::pid_alive(pid, true)),
+            health,
+        });
+    }
+
+    Ok(Status {
+        experiment: config.name.clone(),
+        runner: state.map(|state| RunnerStatus { pid: state.pid, alive, ready: state.ready }),
+        shards,
+    })
+}
+
+pub fn status(config: &config::ExperimentConfig, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let status = collect(config)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    match &status.runner {
+        Some(runner) if runner.alive && runner.ready => println!("🧪 {}: runner up (pid {})", status.experiment, runner.pid),
+        Some(runner) if runner.alive => println!("🧪 {}: runner starting (pid {}), waiting for shards to be healthy", status.experiment, runner.pid),
+        Some(runner) => println!("🧪 {}: runner not running (pid {} died without cleaning up)", status.experiment, runner.pid),
+        None => println!("🧪 {}: runner not running", status.experiment),
+    }
+
+    for shard in &status.shards {
+        let cell = if shard.held_out {
+            "control (held out)".to_string()
+        } else if shard.treatments.is_empty() {
+            "control".to_string()
+        } else {
+            shard.treatments.join(" + ")
+        };
+
+        let head = match (&shard.head, shard.dirty) {
+            (Some(head), Some(true)) => format!("{} (dirty)", &head[..7]),
+            (Some(head), _) => head[..7].to_string(),
+            (None, _) => "missing".to_string(),
+        };
+
+        let build = match &shard.build {
+            Some(build::ShardBuild { finished_at, error: None }) => format!("built {}", ago(*finished_at)),
+            Some(build::ShardBuild { finished_at, error: Some(e) }) => format!("build failed {}: {}", ago(*finished_at), e),
+            None => "never built".to_string(),
+        };
+
+        let process = match (shard.pid, shard.running, shard.health) {
+            (Some(pid), true, Some(health)) => format!("running (pid {}, {})", pid, health_label(health)),
+            (Some(pid), true, None) => format!("running (pid {})", pid),
+            (Some(_), false, _) => "exited".to_string(),
+            (None, _, _) => "stopped".to_string(),
+        };
+
+        println!("  shard {}: {} | {} | {} | {}", shard.shard, cell, head, build, process);
+    }
+
+    Ok(())
+}
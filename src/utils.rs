@@ -1,8 +1,10 @@
 use std::fs;
-use std::io;
+use std::io::{self, IsTerminal, Write};
 use std::path::Path;
 use std::process::Child;
 use std::process::Command;
+use std::process::Stdio;
+use crate::logs;
 
 pub fn copy_dir_recursive(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
     let src = src.as_ref();
@@ -29,7 +31,33 @@ pub fn copy_dir_recursive(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::R
     Ok(())
 }
 
-pub fn run_command_string(cmd_str: &str, working_dir: &str, asynch: bool) -> Result<Child, Box<dyn std::error::Error>> {
+// FNV-1a over every part (with a separator so ("ab", "c") != ("a", "bc")),
+// finished with the splitmix64 mixer so the high bits are usable too. unlike
+// std's DefaultHasher the output of this never changes between releases
+pub fn stable_hash(parts: &[&[u8]]) -> u64 {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let mut hash = OFFSET;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(PRIME);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+// `cmd_str` run by the platform's shell in `working_dir`
+pub fn shell_command(cmd_str: &str, working_dir: impl AsRef<Path>, envs: &[(String, String)]) -> Command {
     #[cfg(unix)]
     let mut command = {
         let mut cmd = Command::new("sh");
@@ -46,17 +74,167 @@ pub fn run_command_string(cmd_str: &str, working_dir: &str, asynch: bool) -> Res
 
     command
         .arg(cmd_str)
-        .current_dir(working_dir);
+        .current_dir(working_dir)
+        .envs(envs.iter().map(|(k, v)| (k, v)));
+
+    command
+}
+
+pub fn run_command_string(
+    cmd_str: &str,
+    working_dir: &str,
+    envs: &[(String, String)],
+    log: Option<&logs::LogTarget>,
+    asynch: bool,
+) -> Result<Child, Box<dyn std::error::Error>> {
+    let mut command = shell_command(cmd_str, working_dir, envs);
+
+    // long-running children get their own process group, so stopping them
+    // also reaches whatever the shell wrapper spawned
+    #[cfg(unix)]
+    if asynch {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    if log.is_some() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
 
     let mut child = command.spawn()?;
+    let pumps = match log {
+        Some(target) => logs::capture(&mut child, target)?,
+        None => vec![],
+    };
+
     if asynch {
         Ok(child)
     } else {
-        child.wait()?;
+        let status = child.wait()?;
+        for pump in pumps {
+            let _ = pump.join();
+        }
+
+        if !status.success() {
+            return Err(format!("`{}` failed with {}", cmd_str, status).into());
+        }
+
         Ok(child)
     }
 }
 
+// SIGTERM (or SIGKILL if `force`) to the child's whole process group
+pub fn signal_group(child: &mut Child, force: bool) {
+    #[cfg(unix)]
+    {
+        let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+        unsafe {
+            libc::killpg(child.id() as libc::pid_t, signal);
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        let _ = force;
+        let _ = child.kill();
+    }
+}
+
+// like signal_group for a process that isn't our child, e.g. one started
+// by another bipolar. `group` signals the whole group `pid` leads
+pub fn signal_pid(pid: u32, group: bool, force: bool) {
+    #[cfg(unix)]
+    {
+        let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+        unsafe {
+            match group {
+                true => libc::killpg(pid as libc::pid_t, signal),
+                false => libc::kill(pid as libc::pid_t, signal),
+            };
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        let _ = (pid, group, force);
+    }
+}
+
+// true while the child or anything left in its process group is running
+pub fn group_alive(child: &mut Child) -> bool {
+    let wrapper_alive = matches!(child.try_wait(), Ok(None));
+
+    #[cfg(unix)]
+    {
+        wrapper_alive || unsafe { libc::killpg(child.id() as libc::pid_t, 0) } == 0
+    }
+
+    #[cfg(windows)]
+    {
+        wrapper_alive
+    }
+}
+
+// whether a process with this pid exists, `group` checks for any process
+// left in the group it leads instead
+pub fn pid_alive(pid: u32, group: bool) -> bool {
+    // a zombie only waits for its parent to reap it, it's as good as gone
+    #[cfg(target_os = "linux")]
+    if !group {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        if stat.rsplit_once(')').is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')) {
+            return false;
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        let result = unsafe {
+            match group {
+                true => libc::killpg(pid as libc::pid_t, 0),
+                false => libc::kill(pid as libc::pid_t, 0),
+            }
+        };
+
+        // EPERM means it's there, it just belongs to someone else
+        result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    #[cfg(windows)]
+    {
+        let _ = (pid, group);
+        false
+    }
+}
+
+// whether anything left in the group `pgid` leads runs somewhere under
+// `dir`, so a pid that got handed to someone else is never taken for ours.
+// only linux can tell, elsewhere nothing is
+pub fn group_runs_in(pgid: u32, dir: &Path) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        let (Ok(dir), Ok(entries)) = (dir.canonicalize(), fs::read_dir("/proc")) else {
+            return false;
+        };
+
+        entries.filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                // after the command come state, ppid and pgrp
+                let stat = fs::read_to_string(entry.path().join("stat")).unwrap_or_default();
+                stat.rsplit_once(')')
+                    .and_then(|(_, rest)| rest.split_whitespace().nth(2))
+                    .and_then(|pgrp| pgrp.parse::<u32>().ok()) == Some(pgid)
+            })
+            .any(|entry| fs::read_link(entry.path().join("cwd")).is_ok_and(|cwd| cwd.starts_with(&dir)))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (pgid, dir);
+        false
+    }
+}
+
 pub fn create_dir_symlink(original: &str, link: &str) -> io::Result<()> {
     let original_path = Path::new(original);
     let link_path = Path::new(link);
@@ -81,3 +259,19 @@ pub fn create_symlink_force(original: &str, link: &str) -> io::Result<()> {
 
     create_dir_symlink(original, link)
 }
+
+// asks on the terminal, anything but y or yes is a no. without a terminal
+// there's nobody to ask, which is a no as well
+pub fn confirm(question: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}